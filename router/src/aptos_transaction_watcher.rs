@@ -1,62 +1,332 @@
-use std::sync::mpsc::{self, Sender};
-use std::collections::HashMap;
-use std::rc::Rc;
-use std::cell::RefCell;
+use std::sync::mpsc::{self, Sender, Receiver, TryRecvError};
 use serde_json::Value;
-use crate::utils::{query_aptos_transactions_by_version, get_aptos_version};
-use crate::types::{Network, ChannelUpdateMetadata};
-use crate::registrys::Registry;
+use crate::data_sources::{AptosDataSource, VersionPrunedError};
+use crate::metrics::{self, WatcherMetrics};
+use crate::config::WatcherConfig;
+use crate::gas::GasSample;
+use crate::types::{Network, ChannelUpdateMetadata, VersionedChange, VersionedEvent, UpdateMode};
 use crate::types::ChannelRegistrysToWatch;
+use std::sync::atomic::Ordering;
 
-pub async fn aptos_watch_transactions(network: &Network, starting_version: u64, tothread_updater_tx: &Sender<ChannelUpdateMetadata>) {
-    let (fromthread_tx, fromthread_rx) = mpsc::channel::<ChannelRegistrysToWatch>();
+fn transaction_version(tx: &Value) -> Option<u64> {
+    tx.get("version").and_then(|x| x.as_str()).and_then(|x| x.parse::<u64>().ok())
+}
+
+//True when the page starts exactly at next_version and has no holes.
+fn is_contiguous(transactions: &Vec<Value>, next_version: u64) -> bool {
+    transactions.iter().enumerate().all(|(i, tx)| transaction_version(tx) == Some(next_version + i as u64))
+}
 
+//Skip to the head of the chain and have the router thread re-read every registry's resources.
+//Returns the next version to watch from.
+async fn resync(source: &dyn AptosDataSource, watcher_metrics: &WatcherMetrics, tothread_updater_tx: &Sender<ChannelUpdateMetadata>) -> Option<u64> {
+    let head_version = match source.get_ledger_version().await {
+        Ok(head_version) => head_version,
+        Err(error) => {
+            println!("Failed to get ledger version for resync: {}", error);
+            return None;
+        }
+    };
+
+    println!("Resyncing registrys at version {}", head_version);
+    watcher_metrics.resyncs.fetch_add(1, Ordering::Relaxed);
+    //A closed channel means the router thread is gone, the watcher notices on its next loop.
     tothread_updater_tx.send(
-        ChannelUpdateMetadata{new_metadata: None, channel_tx: Some(fromthread_tx)}
-    ).unwrap();
+        ChannelUpdateMetadata{new_metadata: None, new_events: None, version: Some(head_version), resync: true, gas_samples: Vec::new(), channel_tx: None}
+    ).ok()?;
+
+    Some(head_version + 1)
+}
 
-    let registrys_to_watch = fromthread_rx.recv().unwrap().registrys_to_watch;
+//Runs until something is sent on shutdown_rx or its sender is dropped.
+pub async fn aptos_watch_transactions(network: &Network, source: &dyn AptosDataSource, config: &WatcherConfig, starting_version: u64, tothread_updater_tx: &Sender<ChannelUpdateMetadata>, shutdown_rx: &Receiver<()>) {
+    let (fromthread_tx, fromthread_rx) = mpsc::channel::<ChannelRegistrysToWatch>();
+
+    let handshake = tothread_updater_tx.send(
+        ChannelUpdateMetadata{new_metadata: None, new_events: None, version: None, resync: false, gas_samples: Vec::new(), channel_tx: Some(fromthread_tx)}
+    );
+    let to_watch = match handshake.ok().and_then(|_| fromthread_rx.recv().ok()) {
+        Some(to_watch) => to_watch,
+        None => return
+    };
+    let registrys_to_watch = to_watch.registrys_to_watch;
+    let event_types_to_watch = to_watch.event_types_to_watch;
+    let swap_event_types = to_watch.swap_event_types;
+    let watch_events = matches!(network.update_mode, UpdateMode::Events { .. });
     println!("Registrys to watch: {:?}", registrys_to_watch);
-    let mut last_version = 0;
-    let mut cur_version = starting_version;
-    // let mut cur_version = 2092570043; //<-- Test Start. Hash Liquidswap
-    // let mut cur_version = 2086051100; //<--- Test Start. Has pancake swap in next version.
-    while(true){
-        let mut changes_map = HashMap::<String, Vec<Value>>::new();
-        let transactions = query_aptos_transactions_by_version(&network.http, cur_version, 10000).await;
-        transactions.iter().for_each(|tx| {
-            let changes: Vec<Value> = tx.get("changes").unwrap().as_array().unwrap().to_vec();
-            changes.iter().for_each(|change| {
-                if(change.get("address").is_some()){
-                    let address = change.get("address").unwrap().to_string().strip_prefix("\"").unwrap().strip_suffix("\"").unwrap().to_string();
-                    
-                    if(!changes_map.contains_key(&address)){
-                        changes_map.insert(address, vec![change.clone()]);
-                    } else {
-                        changes_map.get_mut(&address).unwrap().push(change.clone());
+    if watch_events {
+        println!("Watching events: {:?}", event_types_to_watch);
+    }
+
+    let watcher_metrics = metrics::watcher(&network.name);
+
+    //First version that has not been processed, everything before it has been sent to the router thread.
+    let mut next_version = starting_version;
+    // let mut next_version = 2092570043; //<-- Test Start. Hash Liquidswap
+    // let mut next_version = 2086051100; //<--- Test Start. Has pancake swap in next version.
+    loop {
+        if !matches!(shutdown_rx.try_recv(), Err(TryRecvError::Empty)) {
+            println!("Stopping the {} watcher", network.name);
+            return;
+        }
+
+        let head_version = match source.get_ledger_version().await {
+            Ok(head_version) => head_version,
+            Err(error) => {
+                println!("Failed to get ledger version: {}", error);
+                tokio::time::sleep(tokio::time::Duration::from_millis(config.poll_interval_ms)).await;
+                continue;
+            }
+        };
+        watcher_metrics.set_versions(head_version, next_version.saturating_sub(1));
+
+        if next_version > head_version {
+            tokio::time::sleep(tokio::time::Duration::from_millis(config.poll_interval_ms)).await;
+            continue;
+        }
+
+        let transactions = if watch_events {
+            source.get_transaction_events(next_version, config.transaction_page_limit).await
+        } else {
+            source.get_transactions(next_version, config.transaction_page_limit).await
+        };
+        let transactions = match transactions {
+            Ok(transactions) => transactions,
+            Err(error) if error.downcast_ref::<VersionPrunedError>().is_some() => {
+                println!("{}, falling back to a full resync", error);
+                watcher_metrics.pruned.fetch_add(1, Ordering::Relaxed);
+                if let Some(version) = resync(source, &watcher_metrics, tothread_updater_tx).await {
+                    next_version = version;
+                }
+                continue;
+            }
+            Err(error) => {
+                println!("Failed to get transactions: {}", error);
+                tokio::time::sleep(tokio::time::Duration::from_millis(config.poll_interval_ms)).await;
+                continue;
+            }
+        };
+
+        if transactions.len() == 0 {
+            //The node we hit is behind the head we were given, try again shortly.
+            tokio::time::sleep(tokio::time::Duration::from_millis(config.poll_interval_ms)).await;
+            continue;
+        }
+
+        if !is_contiguous(&transactions, next_version) {
+            println!(
+                "Gap in transactions, expected {} got {:?}, falling back to a full resync",
+                next_version, transaction_version(&transactions[0])
+            );
+            watcher_metrics.gaps.fetch_add(1, Ordering::Relaxed);
+            if let Some(version) = resync(source, &watcher_metrics, tothread_updater_tx).await {
+                next_version = version;
+            }
+            continue;
+        }
+
+        //Every change to a watched registry in this page, in (version, change index) order.
+        //In events mode the events carry the same reserve changes as deltas, applying write-sets too would count them twice.
+        let mut registry_changes: Vec<VersionedChange> = Vec::new();
+        for tx in transactions.iter().filter(|_| !watch_events) {
+            let version = transaction_version(tx).unwrap();
+            let changes = tx.get("changes").and_then(|x| x.as_array()).cloned().unwrap_or(Vec::new());
+            for (index, change) in changes.into_iter().enumerate() {
+                let is_watched = change.get("address")
+                    .and_then(|x| x.as_str())
+                    .map_or(false, |address| registrys_to_watch.iter().any(|x| x == address));
+                if is_watched {
+                    registry_changes.push(VersionedChange { version: version, index: index, change: change });
+                }
+            }
+        }
+        registry_changes.sort_by_key(|x| (x.version, x.index));
+
+        //Every event a registry understands in this page, in (version, event index) order.
+        let mut registry_events: Vec<VersionedEvent> = Vec::new();
+        if watch_events {
+            for tx in &transactions {
+                let version = transaction_version(tx).unwrap();
+                let events = tx.get("events").and_then(|x| x.as_array()).cloned().unwrap_or(Vec::new());
+                for (index, event) in events.into_iter().enumerate() {
+                    let is_watched = event.get("type")
+                        .and_then(|x| x.as_str())
+                        .map_or(false, |type_str| event_types_to_watch.iter().any(|x| type_str.starts_with(&x[..])));
+                    if is_watched {
+                        registry_events.push(VersionedEvent { version: version, index: index, event: event });
                     }
                 }
-            });
-        });
+            }
+            registry_events.sort_by_key(|x| (x.version, x.index));
+        }
 
-        registrys_to_watch.iter().for_each(|registry| {
-            if(changes_map.contains_key(registry)){ 
-                let registry_changes = changes_map.get(registry).unwrap();
-                tothread_updater_tx.send(
-                    ChannelUpdateMetadata{new_metadata: Some(registry_changes.clone()), channel_tx: None}
-                ).unwrap();
+        //Gas used by transactions making a single swap, for calibrating per hop gas estimates.
+        let mut gas_samples: Vec<GasSample> = Vec::new();
+        if network.gas.calibrate {
+            for tx in &transactions {
+                let gas_used = match tx.get("gas_used").and_then(|x| x.as_str()).and_then(|x| x.parse::<u64>().ok()) {
+                    Some(gas_used) => gas_used,
+                    None => continue
+                };
+                let events = tx.get("events").and_then(|x| x.as_array()).cloned().unwrap_or(Vec::new());
+                let swap_protocols: Vec<&String> = events.iter()
+                    .filter_map(|event| event.get("type").and_then(|x| x.as_str()))
+                    .filter_map(|type_str| swap_event_types.iter().find(|(prefix, _)| type_str.starts_with(&prefix[..])).map(|(_, protocol)| protocol))
+                    .collect();
+                if swap_protocols.len() == 1 {
+                    gas_samples.push(GasSample { protocol: swap_protocols[0].clone(), gas_used: gas_used });
+                }
             }
+        }
+
+        let last_version = next_version + transactions.len() as u64 - 1;
+
+        //One message per page. Once it's applied everything up to last_version is reflected in the pairs and can be checkpointed.
+        let sent = tothread_updater_tx.send(ChannelUpdateMetadata{
+            new_metadata: if registry_changes.len() > 0 { Some(registry_changes) } else { None },
+            new_events: if registry_events.len() > 0 { Some(registry_events) } else { None },
+            version: Some(last_version),
+            resync: false,
+            gas_samples: gas_samples,
+            channel_tx: None
         });
+        if sent.is_err() {
+            return;
+        }
 
+        next_version = last_version + 1;
+        watcher_metrics.set_versions(head_version, last_version);
+        println!("Found {} new transactions on {}, lag {}", transactions.len(), network.name, watcher_metrics.lag());
 
-        last_version = cur_version;
-        if transactions.len() > 0 {
-            cur_version = transactions[transactions.len() - 1].get("version").unwrap().as_str().unwrap().parse::<u64>().unwrap();
+        //Keep paging without waiting until we've caught up.
+        if transactions.len() < config.transaction_page_limit as usize {
+            tokio::time::sleep(tokio::time::Duration::from_millis(config.poll_interval_ms)).await;
         }
-        
-        println!("Found {} new transactions", transactions.len());
-        
-        cur_version += 1;
-        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, mpsc::{self, Receiver, Sender}};
+    use std::time::Duration;
+    use std::thread::{self, JoinHandle};
+
+    use serde_json::json;
+
+    use super::aptos_watch_transactions;
+    use crate::config::WatcherConfig;
+    use crate::data_sources::in_memory_data_source::InMemoryDataSource;
+    use crate::types::{Network, ChannelUpdateMetadata, ChannelRegistrysToWatch, UpdateMode};
+
+    const REGISTRY: &str = "0xabc";
+
+    fn test_network(name: &str) -> Network {
+        serde_json::from_value(json!({"name": name, "http": "http://localhost", "chain_id": 4})).unwrap()
+    }
+
+    const SWAP_EVENT: &str = "0xabc::swap::SwapEvent";
+
+    fn transaction(version: u64, address: &str) -> serde_json::Value {
+        json!({
+            "version": version.to_string(),
+            "changes": [{"address": address, "type": "write_resource", "data": {}}],
+            "events": [{"type": format!("{}<0x1::a::A, 0x1::b::B>", SWAP_EVENT), "data": {}}]
+        })
+    }
+
+    //Stops the watcher thread and waits for it when dropped.
+    struct TestWatcher {
+        updater_rx: Receiver<ChannelUpdateMetadata>,
+        shutdown_tx: Sender<()>,
+        handle: Option<JoinHandle<()>>
+    }
+
+    impl Drop for TestWatcher {
+        fn drop(&mut self) {
+            let _ = self.shutdown_tx.send(());
+            if let Some(handle) = self.handle.take() {
+                handle.join().unwrap();
+            }
+        }
+    }
+
+    //Runs the watcher on its own thread and answers its handshake like the router thread does.
+    fn start_watcher(network: Network, source: Arc<InMemoryDataSource>, starting_version: u64) -> TestWatcher {
+        let event_types_to_watch = vec![SWAP_EVENT.to_string()];
+        let (updater_tx, updater_rx) = mpsc::channel::<ChannelUpdateMetadata>();
+        let (shutdown_tx, shutdown_rx) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            let config = WatcherConfig { poll_interval_ms: 10, transaction_page_limit: 100 };
+            rt.block_on(aptos_watch_transactions(&network, &*source, &config, starting_version, &updater_tx, &shutdown_rx));
+        });
+
+        let handshake = updater_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        handshake.channel_tx.unwrap().send(ChannelRegistrysToWatch {
+            registrys_to_watch: vec![REGISTRY.to_string()],
+            event_types_to_watch: event_types_to_watch,
+            swap_event_types: Vec::new()
+        }).unwrap();
+        TestWatcher { updater_rx: updater_rx, shutdown_tx: shutdown_tx, handle: Some(handle) }
+    }
+
+    #[test]
+    fn sends_watched_changes_for_a_contiguous_page() {
+        let source = Arc::new(InMemoryDataSource::new());
+        source.push_transaction(transaction(10, REGISTRY));
+        source.push_transaction(transaction(11, "0xdef"));
+
+        let watcher = start_watcher(test_network("watcher_contiguous"), source, 10);
+        let message = watcher.updater_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert!(!message.resync);
+        assert_eq!(message.version, Some(11));
+        let changes = message.new_metadata.unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].version, 10);
+    }
+
+    #[test]
+    fn events_mode_sends_events_without_write_sets() {
+        let source = Arc::new(InMemoryDataSource::new());
+        source.push_transaction(transaction(10, REGISTRY));
+
+        let mut network = test_network("watcher_events");
+        network.update_mode = UpdateMode::Events { reconcile_interval_secs: 300 };
+        let watcher = start_watcher(network, source, 10);
+        let message = watcher.updater_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert!(message.new_metadata.is_none());
+        assert_eq!(message.new_events.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn resyncs_at_head_on_a_gap() {
+        let source = Arc::new(InMemoryDataSource::new());
+        source.push_transaction(transaction(10, REGISTRY));
+        source.push_transaction(transaction(11, REGISTRY));
+        source.push_transaction(transaction(13, REGISTRY));
+
+        let watcher = start_watcher(test_network("watcher_gap"), source, 10);
+        let message = watcher.updater_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert!(message.resync);
+        assert!(message.new_metadata.is_none());
+        assert_eq!(message.version, Some(13));
+    }
+
+    #[test]
+    fn resyncs_at_head_when_versions_are_pruned() {
+        let source = Arc::new(InMemoryDataSource::new());
+        source.push_transaction(transaction(10, REGISTRY));
+        source.push_transaction(transaction(11, REGISTRY));
+        source.set_pruned_before(11);
+
+        let watcher = start_watcher(test_network("watcher_pruned"), source, 10);
+        let message = watcher.updater_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert!(message.resync);
+        assert_eq!(message.version, Some(11));
+    }
+}
@@ -0,0 +1,177 @@
+use std::{collections::HashMap, rc::Rc, cell::RefCell};
+
+use serde::{Serialize, Deserialize};
+
+use crate::pairs::Pair;
+use crate::pricing::usd_value;
+use crate::router::Route;
+
+fn default_max_hops() -> u8 { 4 }
+
+fn default_probe_divisor() -> u64 { 10000 }
+
+/*
+Cycles are searched for from each base token after every reserve update. Candidates are found on
+log marginal prices (a cycle is profitable at the margin when the log rates around it sum to more than 0),
+then each candidate is sized with Route::max_profit_input to find the input that maximizes profit.
+No base tokens means no searching.
+ */
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ArbitrageConfig {
+    #[serde(default)]
+    pub base_tokens: Vec<String>,
+    #[serde(default = "default_max_hops")]
+    pub max_hops: u8,
+    //Marginal prices are measured with the pool's input reserve divided by this.
+    #[serde(default = "default_probe_divisor")]
+    pub probe_divisor: u64,
+    //Opportunities worth less are dropped, only applied when the base token has a price.
+    #[serde(default)]
+    pub min_profit_usd: f64
+}
+
+impl Default for ArbitrageConfig {
+    fn default() -> Self {
+        ArbitrageConfig {
+            base_tokens: Vec::new(),
+            max_hops: default_max_hops(),
+            probe_divisor: default_probe_divisor(),
+            min_profit_usd: 0.0
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct ArbitrageOpportunity {
+    pub base_token: String,
+    //Starts and ends at base_token.
+    pub path: Vec<String>,
+    pub pair_ids: Vec<String>,
+    pub path_amounts: Vec<u64>,
+    pub input_amount: u64,
+    pub output_amount: u64,
+    pub profit: u64,
+    pub profit_usd: Option<f64>
+}
+
+//Everything found at a version, what the API returns and the stream sends.
+#[derive(Clone, Serialize)]
+pub struct ArbitrageSnapshot {
+    pub version: Option<u64>,
+    pub opportunities: Vec<ArbitrageOpportunity>
+}
+
+struct PartialCycle {
+    log_gain: f64,
+    pairs: Vec<Rc<RefCell<Box<dyn Pair>>>>,
+    path: Vec<String>
+}
+
+fn log_marginal_rate(pair: &dyn Pair, token_in: &String, token_out: &String, probe_divisor: u64) -> Option<f64> {
+    let reserves = pair.reserves()?;
+    let in_index = pair.get_token_arr().iter().position(|x| x == token_in)?;
+    let probe_amount = std::cmp::max(1, reserves[in_index] / probe_divisor);
+    let output_amount = pair.output_amount(probe_amount, token_in, token_out);
+    if output_amount == 0 {
+        return None;
+    }
+    Some((output_amount as f64 / probe_amount as f64).ln())
+}
+
+/*
+Hop limited Bellman-Ford. Log rates add up along a path, so keeping only the best path into each token per hop
+count is exact here, apart from the no pair reuse / no token revisit rules. Returns cycles with a positive log gain.
+ */
+fn find_candidate_cycles(pairs_by_token: &HashMap<String, Vec<Rc<RefCell<Box<dyn Pair>>>>>, base_token: &String, config: &ArbitrageConfig) -> Vec<PartialCycle> {
+    let mut candidates: Vec<PartialCycle> = Vec::new();
+
+    //Token -> Best partial cycle reaching it with this many hops
+    let mut layer: HashMap<String, PartialCycle> = HashMap::new();
+    layer.insert(base_token.clone(), PartialCycle { log_gain: 0.0, pairs: Vec::new(), path: vec![base_token.clone()] });
+
+    for _ in 0..config.max_hops {
+        let mut next_layer: HashMap<String, PartialCycle> = HashMap::new();
+        for (token, partial) in &layer {
+            let matching_pairs = match pairs_by_token.get(token) {
+                Some(matching_pairs) => matching_pairs,
+                None => continue
+            };
+            for pair_ref in matching_pairs {
+                let pair = (**pair_ref).borrow();
+                if partial.pairs.iter().any(|p_ref| (**p_ref).borrow().get_pair_key() == pair.get_pair_key()) {
+                    continue;
+                }
+                for next_token in pair.get_token_arr() {
+                    if next_token == token || (next_token != base_token && partial.path.contains(next_token)) {
+                        continue;
+                    }
+                    let log_rate = match log_marginal_rate(&**pair, token, next_token, config.probe_divisor) {
+                        Some(log_rate) => log_rate,
+                        None => continue
+                    };
+
+                    let mut pairs = partial.pairs.clone();
+                    pairs.push(pair_ref.clone());
+                    let mut path = partial.path.clone();
+                    path.push(next_token.clone());
+                    let next = PartialCycle { log_gain: partial.log_gain + log_rate, pairs: pairs, path: path };
+
+                    if next_token == base_token {
+                        if next.log_gain > 0.0 {
+                            candidates.push(next);
+                        }
+                        continue;
+                    }
+                    if next_layer.get(next_token).map_or(true, |x| x.log_gain < next.log_gain) {
+                        next_layer.insert(next_token.clone(), next);
+                    }
+                }
+            }
+        }
+        if next_layer.len() == 0 {
+            break;
+        }
+        layer = next_layer;
+    }
+
+    candidates
+}
+
+pub fn find_arbitrage(pairs_by_token: &HashMap<String, Vec<Rc<RefCell<Box<dyn Pair>>>>>, prices: &HashMap<String, f64>, config: &ArbitrageConfig) -> Vec<ArbitrageOpportunity> {
+    let mut opportunities: Vec<ArbitrageOpportunity> = Vec::new();
+
+    for base_token in &config.base_tokens {
+        for cycle in find_candidate_cycles(pairs_by_token, base_token, config) {
+            let route = Route { pairs: cycle.pairs, path: cycle.path, path_amounts: Vec::new() };
+            let input_amount = match route.max_profit_input() {
+                Some(input_amount) => input_amount,
+                //Only profitable at the margin, rounding eats it at any real size.
+                None => continue
+            };
+
+            let path_amounts = route.simulate(input_amount);
+            let output_amount = path_amounts[path_amounts.len()-1];
+            let profit = output_amount - input_amount;
+            //A bad price (eg. wrong decimals) can give inf or NaN, count that as unpriced.
+            let profit_usd = usd_value(prices, base_token, profit).filter(|x| x.is_finite());
+            if profit_usd.map_or(false, |x| x < config.min_profit_usd) {
+                continue;
+            }
+
+            opportunities.push(ArbitrageOpportunity {
+                base_token: base_token.clone(),
+                path: route.path.clone(),
+                pair_ids: route.pairs.iter().map(|x| (**x).borrow().get_pair_key().to_string()).collect(),
+                path_amounts: path_amounts,
+                input_amount: input_amount,
+                output_amount: output_amount,
+                profit: profit,
+                profit_usd: profit_usd
+            });
+        }
+    }
+
+    //Most profitable first, unpriced ones last.
+    opportunities.sort_by(|a, b| b.profit_usd.unwrap_or(-1.0).total_cmp(&a.profit_usd.unwrap_or(-1.0)).then(b.profit.cmp(&a.profit)));
+    opportunities
+}
@@ -0,0 +1,105 @@
+use std::{collections::HashMap, fs, rc::Rc, cell::RefCell};
+
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use anyhow::{Result, anyhow};
+
+use crate::{
+    pairs::{Pair, PairMetadata},
+    registrys::Registry,
+    types::Network
+};
+
+pub fn checkpoint_path(network: &Network) -> String {
+    format!("checkpoint.{}.json", network.name)
+}
+
+//Bumped whenever the metadata keys change, older checkpoints are ignored rather than restored into nothing.
+pub const CHECKPOINT_FORMAT: u32 = 2;
+
+//How often the router thread flushes a checkpoint to disk.
+pub const CHECKPOINT_INTERVAL_SECS: u64 = 30;
+
+/*
+A checkpoint is the last ledger version the watcher has fully processed plus a snapshot of 
+every pair's metadata at that version. On restart the snapshot is loaded into the pairs and the
+watcher replays from version + 1, so we don't need the bulk get_metadata resource queries.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    #[serde(default)]
+    pub format: u32,
+    pub network: String,
+    pub version: u64,
+    pub metadata: HashMap<String, HashMap<String, Value>>
+}
+
+pub fn build_checkpoint(network: &Network, version: u64, registrys: &Vec<Box<dyn Registry>>, pairs: &Vec<Rc<RefCell<Box<dyn Pair>>>>) -> Result<Checkpoint> {
+    let mut metadata: HashMap<String, HashMap<String, Value>> = HashMap::new();
+    for registry in registrys {
+        metadata.insert(registry.protocol().to_string(), HashMap::new());
+    }
+
+    for pair_rc_ref in pairs {
+        let pair = (**pair_rc_ref).borrow();
+        let pair_metadata = match pair.get_metadata() {
+            Some(pair_metadata) => pair_metadata,
+            None => continue
+        };
+
+        if let Some(protocol_snapshot) = metadata.get_mut(pair.get_protocol()) {
+            let value = serde_json::to_value(&*pair_metadata)?;
+            protocol_snapshot.insert(pair.metadata_key(), value);
+        }
+    }
+
+    return Ok(Checkpoint {
+        format: CHECKPOINT_FORMAT,
+        network: network.name.clone(),
+        version: version,
+        metadata: metadata
+    });
+}
+
+pub fn write_checkpoint(path: &str, checkpoint: &Checkpoint) -> Result<()> {
+    //Write to a temp file and rename so a crash mid write never leaves a truncated checkpoint.
+    let tmp_path = format!("{}.tmp", path);
+    let json_str = serde_json::to_string(checkpoint)?;
+    fs::write(&tmp_path, json_str)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+pub fn read_checkpoint(path: &str, network: &Network) -> Result<Option<Checkpoint>> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error.into())
+    };
+
+    let checkpoint: Checkpoint = serde_json::from_str(&data)?;
+    if checkpoint.network != network.name {
+        return Err(anyhow!("Checkpoint at {} is for network {}, not {}", path, checkpoint.network, network.name));
+    }
+    if checkpoint.format != CHECKPOINT_FORMAT {
+        println!("Ignoring checkpoint at {} written in format {}, current format is {}", path, checkpoint.format, CHECKPOINT_FORMAT);
+        return Ok(None);
+    }
+    Ok(Some(checkpoint))
+}
+
+pub fn metadata_map_from_checkpoint(registrys: &Vec<Box<dyn Registry>>, checkpoint: &Checkpoint) -> Result<HashMap<String, HashMap<String, Box<dyn PairMetadata>>>> {
+    let mut metadata_map: HashMap<String, HashMap<String, Box<dyn PairMetadata>>> = HashMap::new();
+
+    for registry in registrys {
+        let mut protocol_map: HashMap<String, Box<dyn PairMetadata>> = HashMap::new();
+        if let Some(snapshot) = checkpoint.metadata.get(registry.protocol()) {
+            for (key, value) in snapshot {
+                protocol_map.insert(key.clone(), registry.metadata_from_value(value.clone())?);
+            }
+        }
+        metadata_map.entry(registry.protocol().to_string()).or_insert(HashMap::new()).extend(protocol_map);
+    }
+
+    Ok(metadata_map)
+}
@@ -0,0 +1,198 @@
+use std::{fs, net::IpAddr, path::Path};
+
+use clap::Parser;
+use serde::Deserialize;
+use anyhow::{Result, anyhow};
+
+use crate::utils::{NETWORKS_PATH, REGISTRYS_PATH};
+use crate::router::SearchConfig;
+use crate::quote_cache::QuoteCacheConfig;
+
+//Read if it exists and no --config is given.
+pub const CONFIG_PATH: &str = "router.toml";
+
+//Fullnodes cap /transactions pages at 100, asking for more just returns 100.
+pub const MAX_TRANSACTION_PAGE_LIMIT: u16 = 100;
+
+/*
+Settings are resolved defaults < router.toml < environment < command line.
+Clap handles the last two, anything it leaves as None falls through to the file.
+ */
+#[derive(Parser)]
+#[command(name = "router", about = "Aptos DEX router")]
+pub struct Cli {
+    /// TOML config file [default: router.toml if it exists]
+    #[arg(long, env = "ROUTER_CONFIG")]
+    pub config: Option<String>,
+
+    /// Address the HTTP server binds to
+    #[arg(long, env = "ROUTER_BIND_ADDRESS")]
+    pub bind_address: Option<String>,
+
+    /// Port the HTTP server listens on
+    #[arg(long, env = "ROUTER_PORT")]
+    pub port: Option<u16>,
+
+    /// Networks to index, the first is the default for requests that don't name one
+    #[arg(long, env = "ROUTER_NETWORKS", value_delimiter = ',')]
+    pub networks: Option<Vec<String>>,
+
+    /// Path to networks.json
+    #[arg(long, env = "ROUTER_NETWORKS_PATH")]
+    pub networks_path: Option<String>,
+
+    /// Path to registerys.json
+    #[arg(long, env = "ROUTER_REGISTRYS_PATH")]
+    pub registrys_path: Option<String>,
+
+    /// How long the watcher waits before polling again once caught up
+    #[arg(long, env = "ROUTER_POLL_INTERVAL_MS")]
+    pub poll_interval_ms: Option<u64>,
+
+    /// Transactions the watcher requests per page
+    #[arg(long, env = "ROUTER_TRANSACTION_PAGE_LIMIT")]
+    pub transaction_page_limit: Option<u16>,
+
+    /// Maximum number of hops in a route
+    #[arg(long, env = "ROUTER_MAX_SWAPS")]
+    pub max_swaps: Option<u8>,
+
+    /// Routes kept per token at each hop of the route search
+    #[arg(long, env = "ROUTER_BEAM_WIDTH")]
+    pub beam_width: Option<usize>,
+
+    /// Let routes pass through the same token more than once
+    #[arg(long, env = "ROUTER_ALLOW_TOKEN_REVISITS")]
+    pub allow_token_revisits: Option<bool>,
+
+    /// Load the config and registrys, print what would be indexed and exit
+    #[arg(long)]
+    pub check_config: bool,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatcherConfig {
+    pub poll_interval_ms: u64,
+    pub transaction_page_limit: u16,
+}
+
+impl Default for WatcherConfig {
+    fn default() -> Self {
+        WatcherConfig {
+            poll_interval_ms: 1000,
+            transaction_page_limit: MAX_TRANSACTION_PAGE_LIMIT,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouterConfig {
+    pub bind_address: String,
+    pub port: u16,
+    pub networks: Vec<String>,
+    pub networks_path: String,
+    pub registrys_path: String,
+    pub max_swaps: u8,
+    pub beam_width: usize,
+    pub allow_token_revisits: bool,
+    pub watcher: WatcherConfig,
+    pub quote_cache: QuoteCacheConfig,
+}
+
+impl Default for RouterConfig {
+    fn default() -> Self {
+        RouterConfig {
+            bind_address: String::from("0.0.0.0"),
+            port: 3000,
+            networks: vec![String::from("aptos_mainnet")],
+            networks_path: String::from(NETWORKS_PATH),
+            registrys_path: String::from(REGISTRYS_PATH),
+            max_swaps: 10,
+            beam_width: 4,
+            allow_token_revisits: false,
+            watcher: WatcherConfig::default(),
+            quote_cache: QuoteCacheConfig::default(),
+        }
+    }
+}
+
+impl RouterConfig {
+    pub fn default_network(&self) -> &str {
+        &self.networks[0]
+    }
+
+    pub fn search(&self) -> SearchConfig {
+        SearchConfig {
+            max_swaps: self.max_swaps,
+            beam_width: self.beam_width,
+            allow_token_revisits: self.allow_token_revisits
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        self.bind_address.parse::<IpAddr>()
+            .map_err(|_| anyhow!("bind_address {:?} is not an IP address", self.bind_address))?;
+
+        if self.networks.len() == 0 {
+            return Err(anyhow!("networks is empty, at least one network must be indexed"));
+        }
+        for (i, network) in self.networks.iter().enumerate() {
+            if self.networks[..i].contains(network) {
+                return Err(anyhow!("network {} is listed more than once", network));
+            }
+        }
+
+        if !Path::new(&self.networks_path).exists() {
+            return Err(anyhow!("networks_path {} does not exist", self.networks_path));
+        }
+        if !Path::new(&self.registrys_path).exists() {
+            return Err(anyhow!("registrys_path {} does not exist", self.registrys_path));
+        }
+
+        if self.max_swaps == 0 {
+            return Err(anyhow!("max_swaps must be at least 1"));
+        }
+        if self.beam_width == 0 {
+            return Err(anyhow!("beam_width must be at least 1"));
+        }
+        if self.watcher.poll_interval_ms == 0 {
+            return Err(anyhow!("watcher.poll_interval_ms must be greater than 0"));
+        }
+        if self.watcher.transaction_page_limit == 0 || self.watcher.transaction_page_limit > MAX_TRANSACTION_PAGE_LIMIT {
+            return Err(anyhow!("watcher.transaction_page_limit must be between 1 and {}", MAX_TRANSACTION_PAGE_LIMIT));
+        }
+        if self.quote_cache.buckets_per_doubling == 0 {
+            return Err(anyhow!("quote_cache.buckets_per_doubling must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+pub fn load_config(cli: &Cli) -> Result<RouterConfig> {
+    let path = cli.config.clone().unwrap_or(String::from(CONFIG_PATH));
+
+    let mut config = match fs::read_to_string(&path) {
+        Ok(data) => toml::from_str::<RouterConfig>(&data).map_err(|error| anyhow!("Invalid config {}: {}", path, error))?,
+        //Only the implicit router.toml is optional.
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound && cli.config.is_none() => RouterConfig::default(),
+        Err(error) => return Err(anyhow!("Failed to read config {}: {}", path, error))
+    };
+
+    if let Some(bind_address) = &cli.bind_address { config.bind_address = bind_address.clone(); }
+    if let Some(port) = cli.port { config.port = port; }
+    if let Some(networks) = &cli.networks {
+        config.networks = networks.iter().map(|x| x.trim().to_string()).filter(|x| x.len() > 0).collect();
+    }
+    if let Some(networks_path) = &cli.networks_path { config.networks_path = networks_path.clone(); }
+    if let Some(registrys_path) = &cli.registrys_path { config.registrys_path = registrys_path.clone(); }
+    if let Some(poll_interval_ms) = cli.poll_interval_ms { config.watcher.poll_interval_ms = poll_interval_ms; }
+    if let Some(transaction_page_limit) = cli.transaction_page_limit { config.watcher.transaction_page_limit = transaction_page_limit; }
+    if let Some(max_swaps) = cli.max_swaps { config.max_swaps = max_swaps; }
+    if let Some(beam_width) = cli.beam_width { config.beam_width = beam_width; }
+    if let Some(allow_token_revisits) = cli.allow_token_revisits { config.allow_token_revisits = allow_token_revisits; }
+
+    config.validate()?;
+    Ok(config)
+}
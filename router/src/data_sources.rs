@@ -0,0 +1,121 @@
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::{
+    data_sources::{
+        rest_data_source::RestDataSource,
+        failover_data_source::FailoverDataSource,
+        graphql_data_source::GraphqlDataSource
+    },
+    http_client::HttpClient,
+    types::Network
+};
+
+pub mod rest_data_source;
+pub mod failover_data_source;
+pub mod graphql_data_source;
+#[cfg(feature = "transaction-stream")]
+pub mod transaction_stream_data_source;
+pub mod in_memory_data_source;
+
+/*
+Everything the registrys and the watcher read from chain goes through this trait.
+All implementations return values shaped like the fullnode REST API responses 
+(string encoded u64s, "changes" and "events" on transactions, etc.) so callers don't care where they came from.
+ */
+#[async_trait]
+pub trait AptosDataSource: Send + Sync {
+    async fn get_ledger_version(&self) -> Result<u64>;
+    //event is an event handle, eg. <module_address>::swap::SwapInfo/pair_created
+    async fn get_events(&self, account: &str, event: &str, start: u64, limit: u64) -> Result<Vec<Value>>;
+    async fn get_account_resources(&self, account: &str) -> Result<Vec<Value>>;
+    //Resources as of ledger_version rather than the head, so what's read can be stamped with an exact version.
+    async fn get_account_resources_at(&self, account: &str, ledger_version: u64) -> Result<Vec<Value>>;
+    //Returns an empty vec when start is past the head of the chain, and a VersionPrunedError when start is before the oldest version kept.
+    async fn get_transactions(&self, start: u64, limit: u16) -> Result<Vec<Value>>;
+    //Like get_transactions but only "version" and "events" are guaranteed to be filled in, 
+    //for sources that have events but not write-sets.
+    async fn get_transaction_events(&self, start: u64, limit: u16) -> Result<Vec<Value>> {
+        self.get_transactions(start, limit).await
+    }
+}
+
+//Returned (inside anyhow) when the requested versions have been pruned from the source's history.
+#[derive(Debug)]
+pub struct VersionPrunedError {
+    pub start: u64
+}
+
+impl std::fmt::Display for VersionPrunedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Transactions starting at version {} have been pruned", self.start)
+    }
+}
+
+impl std::error::Error for VersionPrunedError {}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DataSourceConfig {
+    Rest,
+    Graphql {
+        url: String
+    },
+    TransactionStream {
+        url: String,
+        auth_token: Option<String>
+    }
+}
+
+impl DataSourceConfig {
+    //For logs, leaves out the auth token.
+    pub fn describe(&self) -> String {
+        match self {
+            DataSourceConfig::Rest => String::from("rest"),
+            DataSourceConfig::Graphql { url } => format!("graphql ({})", url),
+            DataSourceConfig::TransactionStream { url, .. } => format!("transaction_stream ({})", url)
+        }
+    }
+}
+
+impl Default for DataSourceConfig {
+    fn default() -> Self {
+        DataSourceConfig::Rest
+    }
+}
+
+//The fullnode REST API, failing over between endpoints when the network lists more than one.
+fn build_fullnode_source(network: &Network, client: &HttpClient) -> Result<Box<dyn AptosDataSource>> {
+    if network.http.len() == 0 {
+        return Err(anyhow!("Network {} has no http endpoints", network.name));
+    }
+    if network.http.len() == 1 {
+        return Ok(Box::new(RestDataSource::new(&network.http[0], client.clone())));
+    }
+
+    //Failing over to the next endpoint beats retrying one that's down, so endpoints don't retry themselves.
+    let failover_client = client.without_retries();
+    let endpoints = network.http.iter().map(|http| RestDataSource::new(http, failover_client.clone())).collect();
+    Ok(Box::new(FailoverDataSource::new(network.http.clone(), endpoints, network.failover.clone())))
+}
+
+pub fn build_data_source(network: &Network) -> Result<Arc<dyn AptosDataSource>> {
+    let client = HttpClient::new(network.client.clone());
+    let fullnode = build_fullnode_source(network, &client)?;
+    match &network.data_source {
+        DataSourceConfig::Rest => Ok(Arc::from(fullnode)),
+        DataSourceConfig::Graphql { url } => Ok(Arc::new(GraphqlDataSource::new(url, client, fullnode))),
+        #[cfg(feature = "transaction-stream")]
+        DataSourceConfig::TransactionStream { url, auth_token } => {
+            Ok(Arc::new(transaction_stream_data_source::TransactionStreamDataSource::new(url, auth_token.clone(), fullnode)))
+        }
+        #[cfg(not(feature = "transaction-stream"))]
+        DataSourceConfig::TransactionStream { .. } => {
+            Err(anyhow!("Network {} uses the transaction stream data source but the router was built without the transaction-stream feature", network.name))
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use super::{AptosDataSource, rest_data_source::RestDataSource};
+
+fn default_max_lag_versions() -> u64 { 1000 }
+fn default_health_check_interval_ms() -> u64 { 5000 }
+
+//The "failover" field of a network in networks.json, only used when the network lists more than one endpoint.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FailoverConfig {
+    //An endpoint more than this many versions behind the freshest one is treated as unhealthy.
+    #[serde(default = "default_max_lag_versions")]
+    pub max_lag_versions: u64,
+    #[serde(default = "default_health_check_interval_ms")]
+    pub health_check_interval_ms: u64,
+    //Read resources from two endpoints at the same version and compare them, a third one breaks a disagreement.
+    #[serde(default)]
+    pub quorum_reads: bool
+}
+
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        FailoverConfig {
+            max_lag_versions: default_max_lag_versions(),
+            health_check_interval_ms: default_health_check_interval_ms(),
+            quorum_reads: false
+        }
+    }
+}
+
+struct EndpointHealth {
+    ledger_version: Option<u64>,
+    healthy: bool
+}
+
+/*
+Fullnode REST API spread over several endpoints. Requests go to the first healthy endpoint 
+in the order they're listed and fail over down the list. Health is based on ledger_version 
+freshness and is re-checked every health_check_interval_ms.
+ */
+pub struct FailoverDataSource {
+    urls: Vec<String>,
+    endpoints: Vec<RestDataSource>,
+    health: Mutex<Vec<EndpointHealth>>,
+    last_health_check: Mutex<Option<Instant>>,
+    config: FailoverConfig
+}
+
+impl FailoverDataSource {
+    pub fn new(urls: Vec<String>, endpoints: Vec<RestDataSource>, config: FailoverConfig) -> FailoverDataSource {
+        let health = endpoints.iter().map(|_| EndpointHealth { ledger_version: None, healthy: true }).collect();
+        FailoverDataSource {
+            urls: urls,
+            endpoints: endpoints,
+            health: Mutex::new(health),
+            last_health_check: Mutex::new(None),
+            config: config
+        }
+    }
+
+    async fn check_health(&self) {
+        {
+            let mut last_health_check = self.last_health_check.lock().unwrap();
+            let interval = Duration::from_millis(self.config.health_check_interval_ms);
+            if last_health_check.map_or(false, |x| x.elapsed() < interval) {
+                return;
+            }
+            *last_health_check = Some(Instant::now());
+        }
+
+        let mut versions: Vec<Option<u64>> = Vec::new();
+        for endpoint in &self.endpoints {
+            versions.push(endpoint.get_ledger_version().await.ok());
+        }
+
+        let freshest = versions.iter().filter_map(|x| *x).max().unwrap_or(0);
+        let mut health = self.health.lock().unwrap();
+        for (i, version) in versions.iter().enumerate() {
+            let healthy = match version {
+                Some(version) => version + self.config.max_lag_versions >= freshest,
+                None => false
+            };
+            if health[i].healthy != healthy {
+                println!("Endpoint {} is now {} (version {:?}, freshest {})", self.urls[i], if healthy {"healthy"} else {"unhealthy"}, version, freshest);
+            }
+            health[i] = EndpointHealth { ledger_version: *version, healthy: healthy };
+        }
+    }
+
+    //Healthy endpoints first, unhealthy ones are still tried as a last resort.
+    async fn endpoint_order(&self) -> Vec<usize> {
+        self.check_health().await;
+        let health = self.health.lock().unwrap();
+        let mut order: Vec<usize> = (0..self.endpoints.len()).filter(|i| health[*i].healthy).collect();
+        order.extend((0..self.endpoints.len()).filter(|i| !health[*i].healthy));
+        order
+    }
+
+    fn mark_unhealthy(&self, i: usize) {
+        let mut health = self.health.lock().unwrap();
+        if health[i].healthy {
+            println!("Endpoint {} is now unhealthy", self.urls[i]);
+        }
+        health[i].healthy = false;
+    }
+
+    async fn with_failover<'a, T, F>(&'a self, what: &str, request: F) -> Result<T>
+    where
+        F: Fn(&'a RestDataSource) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<T>> + Send + 'a>>
+    {
+        let mut last_error = anyhow!("No endpoints configured");
+        for i in self.endpoint_order().await {
+            match request(&self.endpoints[i]).await {
+                Ok(result) => return Ok(result),
+                Err(error) => {
+                    println!("Failed to get {} from {}: {}", what, self.urls[i], error);
+                    self.mark_unhealthy(i);
+                    last_error = error;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /*
+    Reads from the two preferred endpoints at ledger_version, or the older of their two ledger versions, so ordinary
+    version skew between them doesn't look like a disagreement. If they still disagree one of them is serving bad state, a third
+    endpoint read at the same version decides which. Without a third endpoint (or when it agrees with neither)
+    we can't tell which is wrong, so neither is demoted and the read fails.
+     */
+    async fn quorum_account_resources(&self, account: &str, ledger_version: Option<u64>) -> Result<Vec<Value>> {
+        let order = self.endpoint_order().await;
+        let (first, second) = (order[0], order[1]);
+
+        let common_version = match ledger_version {
+            Some(ledger_version) => ledger_version,
+            None => {
+                let mut versions: Vec<u64> = Vec::new();
+                for i in [first, second] {
+                    match self.endpoints[i].get_ledger_version().await {
+                        Ok(version) => versions.push(version),
+                        Err(error) => {
+                            println!("Failed to get ledger version from {}: {}", self.urls[i], error);
+                            self.mark_unhealthy(i);
+                            return self.with_failover("resources", |endpoint| Box::pin(endpoint.get_account_resources(account))).await;
+                        }
+                    }
+                }
+                std::cmp::min(versions[0], versions[1])
+            }
+        };
+
+        let first_resources = match self.endpoints[first].get_account_resources_at(account, common_version).await {
+            Ok(resources) => resources,
+            Err(error) => {
+                println!("Failed to get resources from {}: {}", self.urls[first], error);
+                self.mark_unhealthy(first);
+                return self.endpoints[second].get_account_resources_at(account, common_version).await;
+            }
+        };
+        let second_resources = match self.endpoints[second].get_account_resources_at(account, common_version).await {
+            Ok(resources) => resources,
+            Err(error) => {
+                println!("Failed to get resources from {}: {}", self.urls[second], error);
+                self.mark_unhealthy(second);
+                return Ok(first_resources);
+            }
+        };
+
+        if first_resources == second_resources {
+            return Ok(first_resources);
+        }
+        println!(
+            "Resource reads for {} at version {} disagree between {} and {}",
+            account, common_version, self.urls[first], self.urls[second]
+        );
+
+        let third = match order.get(2) {
+            Some(third) => *third,
+            None => return Err(anyhow!("Resource reads for {} at version {} disagree and there's no third endpoint to break the tie", account, common_version))
+        };
+        match self.endpoints[third].get_account_resources_at(account, common_version).await {
+            Ok(third_resources) if third_resources == first_resources => {
+                self.mark_unhealthy(second);
+                Ok(first_resources)
+            }
+            Ok(third_resources) if third_resources == second_resources => {
+                self.mark_unhealthy(first);
+                Ok(second_resources)
+            }
+            Ok(_) => Err(anyhow!("Resource reads for {} at version {} disagree between all of {}, {} and {}", account, common_version, self.urls[first], self.urls[second], self.urls[third])),
+            Err(error) => Err(anyhow!("Resource reads for {} at version {} disagree and {} couldn't break the tie: {}", account, common_version, self.urls[third], error))
+        }
+    }
+}
+
+#[async_trait]
+impl AptosDataSource for FailoverDataSource {
+    async fn get_ledger_version(&self) -> Result<u64> {
+        self.with_failover("ledger version", |endpoint| Box::pin(endpoint.get_ledger_version())).await
+    }
+
+    async fn get_events(&self, account: &str, event: &str, start: u64, limit: u64) -> Result<Vec<Value>> {
+        self.with_failover("events", |endpoint| Box::pin(endpoint.get_events(account, event, start, limit))).await
+    }
+
+    async fn get_account_resources(&self, account: &str) -> Result<Vec<Value>> {
+        if self.config.quorum_reads && self.endpoints.len() >= 2 {
+            return self.quorum_account_resources(account, None).await;
+        }
+        self.with_failover("resources", |endpoint| Box::pin(endpoint.get_account_resources(account))).await
+    }
+
+    async fn get_account_resources_at(&self, account: &str, ledger_version: u64) -> Result<Vec<Value>> {
+        if self.config.quorum_reads && self.endpoints.len() >= 2 {
+            return self.quorum_account_resources(account, Some(ledger_version)).await;
+        }
+        self.with_failover("resources", |endpoint| Box::pin(endpoint.get_account_resources_at(account, ledger_version))).await
+    }
+
+    async fn get_transactions(&self, start: u64, limit: u16) -> Result<Vec<Value>> {
+        self.with_failover("transactions", |endpoint| Box::pin(endpoint.get_transactions(start, limit))).await
+    }
+}
@@ -0,0 +1,210 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use crate::http_client::HttpClient;
+use super::AptosDataSource;
+
+const EVENTS_QUERY: &str = r#"
+query Events($account: String, $creation_number: bigint, $start: bigint, $limit: Int) {
+  events(
+    where: {account_address: {_eq: $account}, creation_number: {_eq: $creation_number}, sequence_number: {_gte: $start}},
+    order_by: {sequence_number: asc},
+    limit: $limit
+  ) {
+    account_address
+    creation_number
+    sequence_number
+    transaction_version
+    type
+    data
+  }
+}
+"#;
+
+const EVENTS_BY_VERSION_QUERY: &str = r#"
+query EventsByVersion($start: bigint, $end: bigint, $offset: Int, $limit: Int) {
+  events(
+    where: {transaction_version: {_gte: $start, _lte: $end}},
+    order_by: [{transaction_version: asc}, {event_index: asc}],
+    offset: $offset,
+    limit: $limit
+  ) {
+    account_address
+    creation_number
+    sequence_number
+    transaction_version
+    type
+    data
+  }
+}
+"#;
+
+//The hosted indexer won't return more rows than this per query.
+const GRAPHQL_PAGE_LIMIT: u64 = 100;
+
+const PROCESSOR_STATUS_QUERY: &str = r#"
+query ProcessorStatus {
+  processor_status(where: {processor: {_eq: "events_processor"}}) {
+    last_success_version
+  }
+}
+"#;
+
+/*
+Aptos indexer GraphQL API, eg. https://api.mainnet.aptoslabs.com/v1/graphql
+The indexer serves events, but not account resources or transaction write-sets,
+so those (and resolving event handles to their creation numbers) go to the fullnode.
+ */
+pub struct GraphqlDataSource {
+    url: String,
+    client: HttpClient,
+    fullnode: Box<dyn AptosDataSource>,
+    //(Account, Event Handle) -> Creation Number
+    creation_numbers: Mutex<HashMap<(String, String), u64>>
+}
+
+impl GraphqlDataSource {
+    pub fn new(url: &str, client: HttpClient, fullnode: Box<dyn AptosDataSource>) -> GraphqlDataSource {
+        GraphqlDataSource {
+            url: url.to_string(),
+            client: client,
+            fullnode: fullnode,
+            creation_numbers: Mutex::new(HashMap::new())
+        }
+    }
+
+    async fn query(&self, query: &str, variables: Value) -> Result<Value> {
+        let body = json!({"query": query, "variables": variables});
+        let resp = self.client.post_json(&self.url, &body).await?;
+
+        let jsn: Value = serde_json::from_str(&resp)?;
+        if let Some(errors) = jsn.get("errors") {
+            return Err(anyhow!("GraphQL query returned errors: {}", errors));
+        }
+        jsn.get("data").cloned().ok_or(anyhow!("GraphQL response has no data"))
+    }
+
+    //Event handles live in a resource field, eg. ::swap::SwapInfo/pair_created, the indexer only knows their creation number.
+    async fn creation_number(&self, account: &str, event: &str) -> Result<u64> {
+        let cache_key = (account.to_string(), event.to_string());
+        if let Some(creation_number) = self.creation_numbers.lock().unwrap().get(&cache_key) {
+            return Ok(*creation_number);
+        }
+
+        let (resource_type, field) = event.split_once('/').ok_or(anyhow!("Invalid event handle: {}", event))?;
+        let resources = self.fullnode.get_account_resources(account).await?;
+        let resource = resources.iter()
+            .find(|x| x.get("type").and_then(|t| t.as_str()) == Some(resource_type))
+            .ok_or(anyhow!("Resource {} not found on {}", resource_type, account))?;
+
+        let creation_number = resource.pointer(&format!("/data/{}/guid/id/creation_num", field))
+            .and_then(value_to_u64)
+            .ok_or(anyhow!("Event handle {} not found on {}", event, account))?;
+
+        self.creation_numbers.lock().unwrap().insert(cache_key, creation_number);
+        Ok(creation_number)
+    }
+}
+
+fn value_to_u64(value: &Value) -> Option<u64> {
+    match value {
+        Value::Number(number) => number.as_u64(),
+        Value::String(string) => string.parse::<u64>().ok(),
+        _ => None
+    }
+}
+
+fn value_to_string(value: Option<&Value>) -> String {
+    value.and_then(value_to_u64).map(|x| x.to_string()).unwrap_or(String::new())
+}
+
+//Reshape an indexer event into what the fullnode returns.
+fn event_to_value(event: &Value) -> Value {
+    json!({
+        "version": value_to_string(event.get("transaction_version")),
+        "guid": {
+            "creation_number": value_to_string(event.get("creation_number")),
+            "account_address": event.get("account_address").cloned().unwrap_or(Value::Null)
+        },
+        "sequence_number": value_to_string(event.get("sequence_number")),
+        "type": event.get("type").cloned().unwrap_or(Value::Null),
+        "data": event.get("data").cloned().unwrap_or(Value::Null)
+    })
+}
+
+#[async_trait]
+impl AptosDataSource for GraphqlDataSource {
+    async fn get_ledger_version(&self) -> Result<u64> {
+        let data = self.query(PROCESSOR_STATUS_QUERY, json!({})).await?;
+        data.pointer("/processor_status/0/last_success_version")
+            .and_then(value_to_u64)
+            .ok_or(anyhow!("GraphQL response has no processor status"))
+    }
+
+    async fn get_events(&self, account: &str, event: &str, start: u64, limit: u64) -> Result<Vec<Value>> {
+        let creation_number = self.creation_number(account, event).await?;
+        let data = self.query(EVENTS_QUERY, json!({
+            "account": account,
+            "creation_number": creation_number,
+            "start": start,
+            "limit": limit
+        })).await?;
+
+        let events = data.get("events").and_then(|x| x.as_array()).ok_or(anyhow!("GraphQL response has no events"))?;
+
+        Ok(events.iter().map(event_to_value).collect())
+    }
+
+    async fn get_account_resources(&self, account: &str) -> Result<Vec<Value>> {
+        self.fullnode.get_account_resources(account).await
+    }
+
+    async fn get_account_resources_at(&self, account: &str, ledger_version: u64) -> Result<Vec<Value>> {
+        self.fullnode.get_account_resources_at(account, ledger_version).await
+    }
+
+    async fn get_transactions(&self, start: u64, limit: u16) -> Result<Vec<Value>> {
+        self.fullnode.get_transactions(start, limit).await
+    }
+
+    async fn get_transaction_events(&self, start: u64, limit: u16) -> Result<Vec<Value>> {
+        let head = self.get_ledger_version().await?;
+        if start > head || limit == 0 {
+            return Ok(Vec::new());
+        }
+        let end = std::cmp::min(start + limit as u64 - 1, head);
+
+        //One entry for every version so the range is contiguous, most won't have any events.
+        let mut transactions: Vec<Value> = (start..=end).map(|version| json!({"version": version.to_string(), "events": []})).collect();
+
+        let mut offset: u64 = 0;
+        loop {
+            let data = self.query(EVENTS_BY_VERSION_QUERY, json!({
+                "start": start,
+                "end": end,
+                "offset": offset,
+                "limit": GRAPHQL_PAGE_LIMIT
+            })).await?;
+            let events = data.get("events").and_then(|x| x.as_array()).ok_or(anyhow!("GraphQL response has no events"))?;
+
+            for event in events {
+                let version = match event.get("transaction_version").and_then(value_to_u64) {
+                    Some(version) if version >= start && version <= end => version,
+                    _ => continue
+                };
+                let tx_events = transactions[(version - start) as usize].get_mut("events").unwrap().as_array_mut().unwrap();
+                tx_events.push(event_to_value(event));
+            }
+
+            if (events.len() as u64) < GRAPHQL_PAGE_LIMIT {
+                break;
+            }
+            offset += GRAPHQL_PAGE_LIMIT;
+        }
+
+        Ok(transactions)
+    }
+}
@@ -0,0 +1,104 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use super::{AptosDataSource, VersionPrunedError};
+
+/*
+Chain state held in memory, for tests and for running the router against fixtures.
+Transactions are REST shaped and must be pushed in version order.
+ */
+#[derive(Default)]
+pub struct InMemoryDataSource {
+    ledger_version: Mutex<u64>,
+    //(Account, Event Handle) -> Events
+    events: Mutex<HashMap<(String, String), Vec<Value>>>,
+    //Account -> Resources
+    resources: Mutex<HashMap<String, Vec<Value>>>,
+    transactions: Mutex<Vec<Value>>,
+    //Transactions before this version are treated as pruned.
+    pruned_before: Mutex<u64>
+}
+
+impl InMemoryDataSource {
+    pub fn new() -> InMemoryDataSource {
+        InMemoryDataSource::default()
+    }
+
+    pub fn set_ledger_version(&self, version: u64) {
+        *self.ledger_version.lock().unwrap() = version;
+    }
+
+    pub fn set_pruned_before(&self, version: u64) {
+        *self.pruned_before.lock().unwrap() = version;
+    }
+
+    pub fn push_event(&self, account: &str, event: &str, data: Value) {
+        let mut events = self.events.lock().unwrap();
+        let handle_events = events.entry((account.to_string(), event.to_string())).or_insert(Vec::new());
+        let sequence_number = handle_events.len();
+        handle_events.push(json!({
+            "version": self.ledger_version.lock().unwrap().to_string(),
+            "sequence_number": sequence_number.to_string(),
+            "type": event,
+            "data": data
+        }));
+    }
+
+    pub fn set_resources(&self, account: &str, resources: Vec<Value>) {
+        self.resources.lock().unwrap().insert(account.to_string(), resources);
+    }
+
+    pub fn push_transaction(&self, transaction: Value) {
+        let version = transaction.get("version").and_then(|x| x.as_str()).and_then(|x| x.parse::<u64>().ok());
+        if let Some(version) = version {
+            let mut ledger_version = self.ledger_version.lock().unwrap();
+            if version > *ledger_version {
+                *ledger_version = version;
+            }
+        }
+        self.transactions.lock().unwrap().push(transaction);
+    }
+}
+
+#[async_trait]
+impl AptosDataSource for InMemoryDataSource {
+    async fn get_ledger_version(&self) -> Result<u64> {
+        Ok(*self.ledger_version.lock().unwrap())
+    }
+
+    async fn get_events(&self, account: &str, event: &str, start: u64, limit: u64) -> Result<Vec<Value>> {
+        let events = self.events.lock().unwrap();
+        let handle_events = match events.get(&(account.to_string(), event.to_string())) {
+            Some(handle_events) => handle_events,
+            None => return Ok(Vec::new())
+        };
+        Ok(handle_events.iter().skip(start as usize).take(limit as usize).cloned().collect())
+    }
+
+    async fn get_account_resources(&self, account: &str) -> Result<Vec<Value>> {
+        Ok(self.resources.lock().unwrap().get(account).cloned().unwrap_or(Vec::new()))
+    }
+
+    //There's no history, resources are whatever was last set.
+    async fn get_account_resources_at(&self, account: &str, _ledger_version: u64) -> Result<Vec<Value>> {
+        self.get_account_resources(account).await
+    }
+
+    async fn get_transactions(&self, start: u64, limit: u16) -> Result<Vec<Value>> {
+        if start < *self.pruned_before.lock().unwrap() {
+            return Err(VersionPrunedError { start: start }.into());
+        }
+        let transactions = self.transactions.lock().unwrap();
+        Ok(transactions.iter()
+            .filter(|tx| {
+                let version = tx.get("version").and_then(|x| x.as_str()).and_then(|x| x.parse::<u64>().ok());
+                version.map_or(false, |version| version >= start)
+            })
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+}
@@ -0,0 +1,68 @@
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use serde_json::Value;
+
+use crate::utils::{
+    query_aptos_events_raw,
+    query_aptos_resources_all_raw,
+    query_aptos_transactions_by_version,
+    get_aptos_version
+};
+use crate::http_client::{HttpClient, HttpStatusError};
+use super::{AptosDataSource, VersionPrunedError};
+
+//Fullnode REST API, eg. https://fullnode.mainnet.aptoslabs.com/v1
+pub struct RestDataSource {
+    http: String,
+    client: HttpClient
+}
+
+impl RestDataSource {
+    pub fn new(http: &str, client: HttpClient) -> RestDataSource {
+        RestDataSource {
+            http: http.to_string(),
+            client: client
+        }
+    }
+}
+
+fn parse_array(raw: &str, what: &str) -> Result<Vec<Value>> {
+    let value: Value = serde_json::from_str(raw).map_err(|e| anyhow!("Failed to parse {}: {}", what, e))?;
+    match value {
+        Value::Array(values) => Ok(values),
+        _ => Err(anyhow!("Expected an array of {}", what))
+    }
+}
+
+#[async_trait]
+impl AptosDataSource for RestDataSource {
+    async fn get_ledger_version(&self) -> Result<u64> {
+        get_aptos_version(&self.client, &self.http).await
+    }
+
+    async fn get_events(&self, account: &str, event: &str, start: u64, limit: u64) -> Result<Vec<Value>> {
+        let raw = query_aptos_events_raw(&self.client, &self.http, account, event, start, limit).await?;
+        parse_array(&raw, "events")
+    }
+
+    async fn get_account_resources(&self, account: &str) -> Result<Vec<Value>> {
+        let raw = query_aptos_resources_all_raw(&self.client, &self.http, account, None).await?;
+        parse_array(&raw, "resources")
+    }
+
+    async fn get_account_resources_at(&self, account: &str, ledger_version: u64) -> Result<Vec<Value>> {
+        let raw = query_aptos_resources_all_raw(&self.client, &self.http, account, Some(ledger_version)).await?;
+        parse_array(&raw, "resources")
+    }
+
+    async fn get_transactions(&self, start: u64, limit: u16) -> Result<Vec<Value>> {
+        match query_aptos_transactions_by_version(&self.client, &self.http, start, limit).await {
+            Ok(transactions) => Ok(transactions),
+            //The fullnode answers 410 Gone for versions it has pruned.
+            Err(error) if error.downcast_ref::<HttpStatusError>().map_or(false, |x| x.status == 410) => {
+                Err(VersionPrunedError { start: start }.into())
+            }
+            Err(error) => Err(error)
+        }
+    }
+}
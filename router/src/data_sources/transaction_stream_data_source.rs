@@ -0,0 +1,161 @@
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use aptos_protos::indexer::v1::{raw_data_client::RawDataClient, GetTransactionsRequest};
+use aptos_protos::transaction::v1::{
+    Event,
+    Transaction,
+    transaction::TxnData,
+    write_set_change::Change
+};
+use tonic::transport::{Channel, ClientTlsConfig};
+
+use super::AptosDataSource;
+
+const MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+
+/*
+Aptos Transaction Stream gRPC service, eg. https://grpc.mainnet.aptoslabs.com:443
+The stream only serves transactions, everything else goes to the fullnode.
+ */
+pub struct TransactionStreamDataSource {
+    url: String,
+    auth_token: Option<String>,
+    fullnode: Box<dyn AptosDataSource>
+}
+
+impl TransactionStreamDataSource {
+    pub fn new(url: &str, auth_token: Option<String>, fullnode: Box<dyn AptosDataSource>) -> TransactionStreamDataSource {
+        TransactionStreamDataSource {
+            url: url.to_string(),
+            auth_token: auth_token,
+            fullnode: fullnode
+        }
+    }
+
+    async fn connect(&self) -> Result<RawDataClient<Channel>> {
+        let mut endpoint = Channel::from_shared(self.url.clone())?;
+        if self.url.starts_with("https") {
+            endpoint = endpoint.tls_config(ClientTlsConfig::new().with_native_roots())?;
+        }
+        let channel = endpoint.connect().await?;
+        Ok(RawDataClient::new(channel)
+            .max_decoding_message_size(MAX_MESSAGE_SIZE)
+            .max_encoding_message_size(MAX_MESSAGE_SIZE))
+    }
+}
+
+fn parse_json(data: &str) -> Value {
+    serde_json::from_str(data).unwrap_or(Value::String(data.to_string()))
+}
+
+fn event_to_value(event: &Event) -> Value {
+    let guid = match &event.key {
+        Some(key) => json!({
+            "creation_number": key.creation_number.to_string(),
+            "account_address": key.account_address
+        }),
+        None => Value::Null
+    };
+    json!({
+        "guid": guid,
+        "sequence_number": event.sequence_number.to_string(),
+        "type": event.type_str,
+        "data": parse_json(&event.data)
+    })
+}
+
+//Reshape a protobuf transaction into what the fullnode returns from /transactions
+fn transaction_to_value(transaction: &Transaction) -> Value {
+    let mut changes: Vec<Value> = Vec::new();
+    if let Some(info) = &transaction.info {
+        for write_set_change in &info.changes {
+            match &write_set_change.change {
+                Some(Change::WriteResource(resource)) => {
+                    changes.push(json!({
+                        "type": "write_resource",
+                        "address": resource.address,
+                        "data": {
+                            "type": resource.type_str,
+                            "data": parse_json(&resource.data)
+                        }
+                    }));
+                }
+                Some(Change::DeleteResource(resource)) => {
+                    changes.push(json!({
+                        "type": "delete_resource",
+                        "address": resource.address,
+                        "resource": resource.type_str
+                    }));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let events: Vec<Value> = match &transaction.txn_data {
+        Some(TxnData::User(user)) => user.events.iter().map(event_to_value).collect(),
+        Some(TxnData::BlockMetadata(block_metadata)) => block_metadata.events.iter().map(event_to_value).collect(),
+        Some(TxnData::Genesis(genesis)) => genesis.events.iter().map(event_to_value).collect(),
+        _ => Vec::new()
+    };
+
+    json!({
+        "version": transaction.version.to_string(),
+        "success": transaction.info.as_ref().map_or(true, |info| info.success),
+        "changes": changes,
+        "events": events
+    })
+}
+
+#[async_trait]
+impl AptosDataSource for TransactionStreamDataSource {
+    async fn get_ledger_version(&self) -> Result<u64> {
+        self.fullnode.get_ledger_version().await
+    }
+
+    async fn get_events(&self, account: &str, event: &str, start: u64, limit: u64) -> Result<Vec<Value>> {
+        self.fullnode.get_events(account, event, start, limit).await
+    }
+
+    async fn get_account_resources(&self, account: &str) -> Result<Vec<Value>> {
+        self.fullnode.get_account_resources(account).await
+    }
+
+    async fn get_account_resources_at(&self, account: &str, ledger_version: u64) -> Result<Vec<Value>> {
+        self.fullnode.get_account_resources_at(account, ledger_version).await
+    }
+
+    async fn get_transactions(&self, start: u64, limit: u16) -> Result<Vec<Value>> {
+        //The stream waits for new transactions at the head of the chain, so don't ask past it.
+        let head = self.fullnode.get_ledger_version().await?;
+        if start > head {
+            return Ok(Vec::new());
+        }
+        let count = std::cmp::min(limit as u64, head - start + 1);
+
+        let mut client = self.connect().await?;
+        let mut request = tonic::Request::new(GetTransactionsRequest {
+            starting_version: Some(start),
+            transactions_count: Some(count),
+            ..Default::default()
+        });
+        if let Some(auth_token) = &self.auth_token {
+            request.metadata_mut().insert("authorization", format!("Bearer {}", auth_token).parse()?);
+        }
+
+        let mut stream = client.get_transactions(request).await?.into_inner();
+        let mut transactions: Vec<Value> = Vec::new();
+        while let Some(response) = stream.message().await.map_err(|e| anyhow!("Transaction stream failed: {}", e))? {
+            for transaction in &response.transactions {
+                transactions.push(transaction_to_value(transaction));
+            }
+            if transactions.len() as u64 >= count {
+                break;
+            }
+        }
+
+        Ok(transactions)
+    }
+}
@@ -0,0 +1,145 @@
+use std::{collections::HashMap, rc::Rc};
+
+use serde::{Serialize, Deserialize};
+
+use crate::router::Route;
+
+fn default_base_gas_units() -> u64 { 500 }
+
+fn default_hop_gas_units() -> HashMap<String, u64> {
+    let mut hop_gas_units: HashMap<String, u64> = HashMap::new();
+    hop_gas_units.insert(String::from("pancake"), 800);
+    hop_gas_units.insert(String::from("liquidswap_constant_product"), 1200);
+    hop_gas_units
+}
+
+fn default_unknown_hop_gas_units() -> u64 { 1500 }
+
+fn default_gas_price() -> u64 { 100 }
+
+fn default_gas_token() -> String { String::from("0x1::aptos_coin::AptosCoin") }
+
+fn default_calibration_weight() -> f64 { 0.05 }
+
+/*
+Gas units a route costs are base_gas_units for the transaction plus one hop estimate per swap.
+With calibrate on, each protocol's hop estimate follows the gas used by transactions the watcher sees
+doing a single swap on it, an exponential moving average weighted by calibration_weight.
+ */
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GasConfig {
+    #[serde(default = "default_base_gas_units")]
+    pub base_gas_units: u64,
+    //Protocol -> Gas units per hop
+    #[serde(default = "default_hop_gas_units")]
+    pub hop_gas_units: HashMap<String, u64>,
+    #[serde(default = "default_unknown_hop_gas_units")]
+    pub unknown_hop_gas_units: u64,
+    //Octas per gas unit, used when the request doesn't give one.
+    #[serde(default = "default_gas_price")]
+    pub default_gas_price: u64,
+    #[serde(default = "default_gas_token")]
+    pub gas_token: String,
+    #[serde(default)]
+    pub calibrate: bool,
+    #[serde(default = "default_calibration_weight")]
+    pub calibration_weight: f64
+}
+
+impl Default for GasConfig {
+    fn default() -> Self {
+        GasConfig {
+            base_gas_units: default_base_gas_units(),
+            hop_gas_units: default_hop_gas_units(),
+            unknown_hop_gas_units: default_unknown_hop_gas_units(),
+            default_gas_price: default_gas_price(),
+            gas_token: default_gas_token(),
+            calibrate: false,
+            calibration_weight: default_calibration_weight()
+        }
+    }
+}
+
+//Gas used by a transaction that made exactly one swap, on protocol.
+pub struct GasSample {
+    pub protocol: String,
+    pub gas_used: u64
+}
+
+pub struct GasModel {
+    pub config: GasConfig,
+    //Protocol -> Calibrated gas units per hop
+    calibrated: HashMap<String, f64>
+}
+
+impl GasModel {
+    pub fn new(config: GasConfig) -> GasModel {
+        GasModel { config: config, calibrated: HashMap::new() }
+    }
+
+    pub fn observe(&mut self, sample: &GasSample) {
+        if !self.config.calibrate {
+            return;
+        }
+        let hop_gas = sample.gas_used.saturating_sub(self.config.base_gas_units) as f64;
+        let weight = self.config.calibration_weight;
+        let estimate = self.calibrated.entry(sample.protocol.clone()).or_insert(hop_gas);
+        *estimate = *estimate * (1.0 - weight) + hop_gas * weight;
+    }
+
+    pub fn hop_gas_units(&self, protocol: &str) -> u64 {
+        if let Some(calibrated) = self.calibrated.get(protocol) {
+            return *calibrated as u64;
+        }
+        *self.config.hop_gas_units.get(protocol).unwrap_or(&self.config.unknown_hop_gas_units)
+    }
+
+    pub fn route_gas_units(&self, route: &Route) -> u64 {
+        let hops: u64 = route.pairs.iter().map(|x| self.hop_gas_units((**x).borrow().get_protocol())).sum();
+        self.config.base_gas_units + hops
+    }
+}
+
+pub struct RankedRoute {
+    pub route: Rc<Route>,
+    pub gas_units: u64,
+    //In the gas token.
+    pub gas_cost: u64,
+    //None when the gas token can't be routed into the output token, the route is then ranked on gross output.
+    pub gas_cost_in_output: Option<u64>,
+    pub net_output: u64
+}
+
+/*
+Ranks by output after gas, best first. gas_to_output converts an amount of the gas token into the
+output token, it's only called once, with the most expensive route's cost, and the rate is reused
+for the rest since gas costs are small enough that price impact doesn't matter.
+ */
+pub fn rank_routes_by_net_output(routes: Vec<Rc<Route>>, gas_model: &GasModel, gas_price: u64, gas_to_output: impl FnOnce(u64) -> Option<u64>) -> Vec<RankedRoute> {
+    let gas_units: Vec<u64> = routes.iter().map(|x| gas_model.route_gas_units(x)).collect();
+    //gas_price comes from the request, saturate rather than overflow on an absurd one.
+    let max_gas_cost = gas_units.iter().max().map_or(0, |x| x.saturating_mul(gas_price));
+
+    //Output token per gas token.
+    let rate = match max_gas_cost {
+        0 => None,
+        _ => gas_to_output(max_gas_cost).map(|x| x as f64 / max_gas_cost as f64)
+    };
+
+    let mut ranked: Vec<RankedRoute> = routes.into_iter().zip(gas_units.into_iter()).map(|(route, gas_units)| {
+        let gas_cost = gas_units.saturating_mul(gas_price);
+        let gas_cost_in_output = rate.map(|rate| (gas_cost as f64 * rate).ceil() as u64);
+        let net_output = route.output_amount().saturating_sub(gas_cost_in_output.unwrap_or(0));
+        RankedRoute {
+            route: route,
+            gas_units: gas_units,
+            gas_cost: gas_cost,
+            gas_cost_in_output: gas_cost_in_output,
+            net_output: net_output
+        }
+    }).collect();
+
+    //Fewer hops wins a tie.
+    ranked.sort_by(|a, b| b.net_output.cmp(&a.net_output).then(a.route.pairs.len().cmp(&b.route.pairs.len())));
+    ranked
+}
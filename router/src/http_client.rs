@@ -0,0 +1,245 @@
+use std::{collections::HashMap, fmt, sync::{Mutex, OnceLock}};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde::{Serialize, Deserialize};
+
+const CONNECT_TIMEOUT_MS: u64 = 5000;
+const POOL_IDLE_TIMEOUT_SECS: u64 = 90;
+
+//One client for the whole process so connections to each node are pooled and reused.
+static SHARED_CLIENT: OnceLock<Client> = OnceLock::new();
+
+//Endpoint (scheme://host:port) -> Token Bucket, shared by every HttpClient hitting that endpoint.
+static RATE_LIMITERS: OnceLock<Mutex<HashMap<String, TokenBucket>>> = OnceLock::new();
+
+fn shared_client() -> &'static Client {
+    SHARED_CLIENT.get_or_init(|| {
+        Client::builder()
+            .connect_timeout(Duration::from_millis(CONNECT_TIMEOUT_MS))
+            .pool_idle_timeout(Duration::from_secs(POOL_IDLE_TIMEOUT_SECS))
+            .build()
+            .expect("Failed to build http client")
+    })
+}
+
+fn default_timeout_ms() -> u64 { 10000 }
+fn default_max_retries() -> u32 { 5 }
+fn default_initial_backoff_ms() -> u64 { 250 }
+fn default_max_backoff_ms() -> u64 { 10000 }
+fn default_api_key_header() -> String { String::from("Authorization") }
+
+//Per network http settings, the "client" field of a network in networks.json
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HttpClientConfig {
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    //No rate limiting when unset.
+    #[serde(default)]
+    pub requests_per_second: Option<f64>,
+    #[serde(default)]
+    pub burst: Option<u32>,
+    #[serde(default)]
+    pub api_key: Option<String>,
+    //Sent as "Bearer <api_key>" when this is Authorization, otherwise the raw key.
+    #[serde(default = "default_api_key_header")]
+    pub api_key_header: String
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        HttpClientConfig {
+            timeout_ms: default_timeout_ms(),
+            max_retries: default_max_retries(),
+            initial_backoff_ms: default_initial_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+            requests_per_second: None,
+            burst: None,
+            api_key: None,
+            api_key_header: default_api_key_header()
+        }
+    }
+}
+
+//Returned (inside anyhow) for responses that failed with a status we don't retry, or ran out of retries.
+#[derive(Debug)]
+pub struct HttpStatusError {
+    pub status: u16,
+    pub body: String
+}
+
+impl fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed with status code: {} {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant
+}
+
+impl TokenBucket {
+    fn new(refill_per_sec: f64, capacity: f64) -> TokenBucket {
+        TokenBucket {
+            capacity: capacity,
+            tokens: capacity,
+            refill_per_sec: refill_per_sec,
+            last_refill: Instant::now()
+        }
+    }
+
+    //Takes a token if one is available, otherwise returns how long until one will be.
+    fn try_take(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return None;
+        }
+        Some(Duration::from_secs_f64((1.0 - self.tokens) / self.refill_per_sec))
+    }
+}
+
+fn endpoint_of(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) => format!("{}://{}:{}", parsed.scheme(), parsed.host_str().unwrap_or(""), parsed.port_or_known_default().unwrap_or(0)),
+        Err(_) => url.to_string()
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+//Only the delay-seconds form of Retry-After, anything else falls back to our own backoff.
+fn retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    headers.get(reqwest::header::RETRY_AFTER)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+#[derive(Clone)]
+pub struct HttpClient {
+    config: HttpClientConfig
+}
+
+impl HttpClient {
+    pub fn new(config: HttpClientConfig) -> HttpClient {
+        HttpClient {
+            config: config
+        }
+    }
+
+    //Same settings but gives up on the first failure, for callers that have somewhere else to retry.
+    pub fn without_retries(&self) -> HttpClient {
+        let mut config = self.config.clone();
+        config.max_retries = 0;
+        HttpClient::new(config)
+    }
+
+    async fn acquire(&self, url: &str) {
+        let refill_per_sec = match self.config.requests_per_second {
+            Some(requests_per_second) if requests_per_second > 0.0 => requests_per_second,
+            _ => return
+        };
+        let capacity = self.config.burst.unwrap_or(1).max(1) as f64;
+        let endpoint = endpoint_of(url);
+
+        loop {
+            let wait = {
+                let mut rate_limiters = RATE_LIMITERS.get_or_init(|| Mutex::new(HashMap::new())).lock().unwrap();
+                let bucket = rate_limiters.entry(endpoint.clone()).or_insert_with(|| TokenBucket::new(refill_per_sec, capacity));
+                bucket.try_take()
+            };
+            match wait {
+                None => return,
+                Some(wait) => tokio::time::sleep(wait).await
+            }
+        }
+    }
+
+    fn with_api_key(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.config.api_key {
+            Some(api_key) if self.config.api_key_header.eq_ignore_ascii_case("authorization") => {
+                request.header("Authorization", format!("Bearer {}", api_key))
+            }
+            Some(api_key) => request.header(&self.config.api_key_header, api_key),
+            None => request
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        let backoff_ms = self.config.initial_backoff_ms.saturating_mul(1u64 << attempt.min(16));
+        Duration::from_millis(backoff_ms.min(self.config.max_backoff_ms))
+    }
+
+    //Sends the request built by build_request, retrying 429s, 5xxs and connection errors with exponential backoff.
+    async fn send<F>(&self, url: &str, build_request: F) -> Result<String>
+    where
+        F: Fn(&Client) -> RequestBuilder
+    {
+        let mut attempt: u32 = 0;
+        loop {
+            self.acquire(url).await;
+
+            let request = self.with_api_key(build_request(shared_client()))
+                .timeout(Duration::from_millis(self.config.timeout_ms));
+
+            let wait = match request.send().await {
+                Ok(resp) => {
+                    let status = resp.status();
+                    if status.is_success() {
+                        return Ok(resp.text().await?);
+                    }
+
+                    let server_wait = retry_after(resp.headers());
+                    let body = resp.text().await.unwrap_or(String::new());
+                    if !is_retryable(status) || attempt >= self.config.max_retries {
+                        return Err(HttpStatusError { status: status.as_u16(), body: body }.into());
+                    }
+                    println!("Request to {} failed with status code: {}, retrying", url, status);
+                    server_wait.unwrap_or(self.backoff(attempt))
+                }
+                Err(error) => {
+                    if attempt >= self.config.max_retries {
+                        return Err(anyhow!("Request to {} failed: {}", url, error));
+                    }
+                    println!("Request to {} failed: {}, retrying", url, error);
+                    self.backoff(attempt)
+                }
+            };
+
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+        }
+    }
+
+    pub async fn get(&self, url: &str) -> Result<String> {
+        self.send(url, |client| client.get(url)).await
+    }
+
+    pub async fn post_json(&self, url: &str, body: &serde_json::Value) -> Result<String> {
+        let body_str = body.to_string();
+        self.send(url, |client| {
+            client.post(url)
+                .header("content-type", "application/json")
+                .body(body_str.clone())
+        }).await
+    }
+}
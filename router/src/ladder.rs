@@ -0,0 +1,281 @@
+use std::{collections::HashMap, rc::Rc, cell::RefCell};
+
+use serde::{Serialize, Deserialize};
+
+use crate::pairs::Pair;
+use crate::pruning::PruningConfig;
+use crate::quote_cache::QuoteCache;
+use crate::router::{Route, SearchConfig};
+
+//Most amounts a ladder can have, each one is a route search.
+pub const MAX_LADDER_STEPS: usize = 100;
+
+//Most depth percents a ladder can ask for, each one is a bisection per route.
+pub const MAX_DEPTH_PERCENTS: usize = 10;
+
+//Simulations per route per depth percent. A route's share comes out within its max input / 2^steps below the exact amount.
+const MAX_DEPTH_BISECTION_STEPS: u32 = 20;
+
+fn default_steps() -> usize { 20 }
+
+fn default_depth_percents() -> Vec<f64> { vec![0.5, 1.0, 2.0, 5.0] }
+
+#[derive(Deserialize)]
+pub struct LadderRequest {
+    #[serde(default)]
+    pub network: Option<String>,
+    pub token_in: String,
+    pub token_out: String,
+    //Either the amounts to quote, or min_amount and max_amount to space steps amounts logarithmically between.
+    #[serde(default)]
+    pub amounts: Option<Vec<u64>>,
+    #[serde(default)]
+    pub min_amount: Option<u64>,
+    #[serde(default)]
+    pub max_amount: Option<u64>,
+    #[serde(default = "default_steps")]
+    pub steps: usize,
+    //Percent below mid price to report depth at.
+    #[serde(default = "default_depth_percents")]
+    pub depth_percents: Vec<f64>,
+}
+
+#[derive(Serialize)]
+pub struct LadderStep {
+    pub input_amount: u64,
+    pub output_amount: u64,
+    pub path: Vec<String>,
+    //Output per input, in smallest units.
+    pub execution_price: f64,
+    //Against mid_price.
+    pub price_impact: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct LadderDepth {
+    pub percent: f64,
+    //Summed over the routes that each execute within percent of mid price, routes sharing a pool with a better one are left out.
+    pub input_amount: u64,
+    pub output_amount: u64,
+    pub routes: usize,
+}
+
+#[derive(Serialize)]
+pub struct LadderResponseBody {
+    pub version: Option<u64>,
+    //Best marginal price of the routes found for the smallest amount.
+    pub mid_price: Option<f64>,
+    pub steps: Vec<LadderStep>,
+    pub depth: Vec<LadderDepth>,
+}
+
+fn ladder_amounts(request: &LadderRequest) -> Result<Vec<u64>, String> {
+    let mut amounts = match (&request.amounts, request.min_amount, request.max_amount) {
+        (Some(amounts), _, _) => amounts.clone(),
+        (None, Some(min_amount), Some(max_amount)) => {
+            if min_amount == 0 || min_amount > max_amount {
+                return Err(String::from("min_amount must be greater than 0 and at most max_amount"));
+            }
+            if request.steps < 2 {
+                return Err(String::from("steps must be at least 2"));
+            }
+            if request.steps > MAX_LADDER_STEPS {
+                return Err(format!("At most {} steps per ladder", MAX_LADDER_STEPS));
+            }
+            let ratio = (max_amount as f64 / min_amount as f64).powf(1.0 / (request.steps - 1) as f64);
+            (0..request.steps).map(|i| ((min_amount as f64) * ratio.powi(i as i32)).round() as u64).collect()
+        }
+        _ => return Err(String::from("Give either amounts or min_amount and max_amount"))
+    };
+
+    amounts.retain(|x| *x > 0);
+    amounts.sort();
+    amounts.dedup();
+    if amounts.len() == 0 {
+        return Err(String::from("No amounts greater than 0"));
+    }
+    if amounts.len() > MAX_LADDER_STEPS {
+        return Err(format!("At most {} amounts per ladder", MAX_LADDER_STEPS));
+    }
+    Ok(amounts)
+}
+
+fn best_route(
+    quote_cache: &mut QuoteCache,
+    pairs_by_token: &HashMap<String, Vec<Rc<RefCell<Box<dyn Pair>>>>>,
+    request: &LadderRequest,
+    input_amount: u64,
+    search: &SearchConfig,
+    pruning: &PruningConfig
+) -> Option<Rc<Route>> {
+    quote_cache.find_routes(pairs_by_token, &request.token_in, &request.token_out, input_amount, search, pruning).first().cloned()
+}
+
+//The most route can take with its execution price at or above min_price. No route can take more than all of
+//token_in in its first pool, execution price falls with size so bisecting on amount is monotone.
+fn route_depth(route: &Route, min_price: f64) -> (u64, u64) {
+    let mut hi = {
+        let pair = (*route.pairs[0]).borrow();
+        match (pair.reserves(), pair.get_token_arr().iter().position(|x| *x == route.path[0])) {
+            (Some(reserves), Some(index)) => reserves[index],
+            _ => return (0, 0)
+        }
+    };
+
+    let mut lo = 0u64;
+    let mut lo_output = 0u64;
+    let mut bisection_steps = 0;
+    while lo < hi && bisection_steps < MAX_DEPTH_BISECTION_STEPS {
+        bisection_steps += 1;
+        let mid = lo + (hi - lo + 1) / 2;
+        let output_amount = route.simulate_output(mid);
+        if output_amount as f64 / mid as f64 >= min_price {
+            lo = mid;
+            lo_output = output_amount;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    (lo, lo_output)
+}
+
+/*
+Each amount is quoted on its own best route, so the ladder shows routing switching over as size grows.
+Depth is summed over the routes found for the smallest amount, best first, skipping any route that shares
+a pool with one already counted so the same liquidity isn't counted twice. Each route's share is found by
+bisecting on amount for where its execution price drops below mid price by the given percent. The bisection
+only simulates, it's capped so a big ladder stays cheap, a share is the largest amount found within percent when it stops.
+ */
+pub fn build_ladder(
+    version: Option<u64>,
+    pairs_by_token: &HashMap<String, Vec<Rc<RefCell<Box<dyn Pair>>>>>,
+    quote_cache: &mut QuoteCache,
+    request: &LadderRequest,
+    search: &SearchConfig,
+    pruning: &PruningConfig
+) -> Result<LadderResponseBody, String> {
+    if request.token_in == request.token_out {
+        return Err(String::from("token_in and token_out must differ"));
+    }
+    let amounts = ladder_amounts(request)?;
+    if request.depth_percents.len() > MAX_DEPTH_PERCENTS {
+        return Err(format!("At most {} depth_percents per ladder", MAX_DEPTH_PERCENTS));
+    }
+    if request.depth_percents.iter().any(|x| !(*x > 0.0 && *x < 100.0)) {
+        return Err(String::from("depth_percents must be between 0 and 100"));
+    }
+
+    let candidates = quote_cache.find_routes(pairs_by_token, &request.token_in, &request.token_out, amounts[0], search, pruning);
+    let mid_price = candidates.iter().filter_map(|x| x.marginal_price()).fold(None, |a: Option<f64>, b| Some(a.map_or(b, |a| a.max(b))));
+
+    let mut steps: Vec<LadderStep> = Vec::new();
+    for input_amount in &amounts {
+        let route = match best_route(quote_cache, pairs_by_token, request, *input_amount, search, pruning) {
+            Some(route) => route,
+            None => continue
+        };
+        let execution_price = route.output_amount() as f64 / *input_amount as f64;
+        steps.push(LadderStep {
+            input_amount: *input_amount,
+            output_amount: route.output_amount(),
+            path: route.path.clone(),
+            execution_price: execution_price,
+            price_impact: mid_price.map(|mid_price| (1.0 - execution_price / mid_price).max(0.0)),
+        });
+    }
+
+    let mut depth: Vec<LadderDepth> = Vec::new();
+    if let Some(mid_price) = mid_price {
+        for percent in &request.depth_percents {
+            let min_price = mid_price * (1.0 - percent / 100.0);
+            let mut used: Vec<Rc<RefCell<Box<dyn Pair>>>> = Vec::new();
+            let mut total = LadderDepth { percent: *percent, input_amount: 0, output_amount: 0, routes: 0 };
+            for route in &candidates {
+                if route.pairs.iter().any(|x| used.iter().any(|y| Rc::ptr_eq(x, y))) {
+                    continue;
+                }
+                let (input_amount, output_amount) = route_depth(route, min_price);
+                if input_amount == 0 {
+                    continue;
+                }
+                used.extend(route.pairs.iter().cloned());
+                total.input_amount = total.input_amount.saturating_add(input_amount);
+                total.output_amount = total.output_amount.saturating_add(output_amount);
+                total.routes += 1;
+            }
+            depth.push(total);
+        }
+    }
+
+    Ok(LadderResponseBody { version: version, mid_price: mid_price, steps: steps, depth: depth })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell};
+
+    use serde_json::json;
+
+    use super::{LadderRequest, MAX_LADDER_STEPS, build_ladder, route_depth};
+    use crate::metrics;
+    use crate::pairs::Pair;
+    use crate::pairs::pancake_pair::{PancakePair, PancakeMetadata, pancake_pair_id};
+    use crate::pruning::{PruningConfig, build_pairs_by_token};
+    use crate::quote_cache::{QuoteCache, QuoteCacheConfig};
+    use crate::router::{Route, SearchConfig};
+
+    fn pair(module_address: &str, token_x: &str, token_y: &str) -> Rc<RefCell<Box<dyn Pair>>> {
+        Rc::new(RefCell::new(Box::new(PancakePair {
+            network: String::from("mainnet"),
+            protocol: String::from("pancake"),
+            pair_key: pancake_pair_id(module_address, token_x, token_y).key(),
+            pool_addr: String::from(module_address),
+            token_arr: vec![token_x.to_string(), token_y.to_string()],
+            router_pair_addr: String::new(),
+            metadata: PancakeMetadata { reserves: Some(vec![1000000000, 1000000000]), last_updated_version: None }
+        })))
+    }
+
+    fn request(value: serde_json::Value) -> LadderRequest {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn rejects_too_many_steps() {
+        let metrics = metrics::router("ladder_test_steps");
+        let mut quote_cache = QuoteCache::new(QuoteCacheConfig::default(), metrics);
+        let pairs_by_token = build_pairs_by_token(&vec![pair("0x1", "A", "B")], &PruningConfig::default());
+        let search = SearchConfig { max_swaps: 3, beam_width: 4, allow_token_revisits: false };
+
+        let request = request(json!({ "token_in": "A", "token_out": "B", "min_amount": 1, "max_amount": 1000, "steps": MAX_LADDER_STEPS + 1 }));
+        assert!(build_ladder(None, &pairs_by_token, &mut quote_cache, &request, &search, &PruningConfig::default()).is_err());
+    }
+
+    //Two identical A-B pools and A-C-B. Each pool can take as much as the other, A-C-B adds a little more at 1%.
+    #[test]
+    fn depth_is_summed_over_routes_that_dont_share_pools() {
+        let pairs = vec![
+            pair("0x1", "A", "B"),
+            pair("0x2", "A", "B"),
+            pair("0x1", "A", "C"),
+            pair("0x1", "C", "B"),
+        ];
+        let pruning = PruningConfig::default();
+        let search = SearchConfig { max_swaps: 3, beam_width: 4, allow_token_revisits: false };
+        let pairs_by_token = build_pairs_by_token(&pairs, &pruning);
+        let mut quote_cache = QuoteCache::new(QuoteCacheConfig::default(), metrics::router("ladder_test_depth"));
+
+        let request = request(json!({ "token_in": "A", "token_out": "B", "amounts": [1000], "depth_percents": [1.0] }));
+        let ladder = build_ladder(None, &pairs_by_token, &mut quote_cache, &request, &search, &pruning).unwrap();
+        let mid_price = ladder.mid_price.unwrap();
+
+        let direct = Route { pairs: vec![pairs[0].clone()], path: vec![String::from("A"), String::from("B")], path_amounts: Vec::new() };
+        let (single_input, _) = route_depth(&direct, mid_price * 0.99);
+        assert!(single_input > 0);
+
+        let depth = &ladder.depth[0];
+        assert_eq!(depth.routes, 3);
+        assert!(depth.input_amount > 2 * single_input);
+        assert!(depth.output_amount as f64 / depth.input_amount as f64 >= mid_price * 0.99);
+    }
+}
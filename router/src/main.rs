@@ -1,8 +1,8 @@
 #![allow(dead_code)]
 
 use axum::{
-    extract::{Path, State},
-    response::IntoResponse,
+    extract::{Path, Query, State, ws::{Message, WebSocket, WebSocketUpgrade}},
+    response::{IntoResponse, sse::{Event, KeepAlive, Sse}},
     routing::{get, post},
     http::StatusCode,
     Json, Router,
@@ -10,15 +10,20 @@ use axum::{
 
 use tracing_subscriber;
 use tokio;
-use std::collections::HashMap;
+use tokio::sync::{broadcast, mpsc as tokio_mpsc};
+use tokio_stream::{Stream, StreamExt, wrappers::{BroadcastStream, errors::BroadcastStreamRecvError}};
+use clap::Parser;
+use std::collections::{HashMap, HashSet};
 use std::sync::mpsc::{self, RecvTimeoutError, RecvError};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::thread;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::convert::Infallible;
 use std::rc::Rc;
 use std::cell::RefCell;
 
 use crate::pairs::PairMetadata;
-use crate::pairs::PairNames;
 use crate::pairs::Pair;
 use crate::registrys::Registry;
 use crate::registrys::pancake_registry::PancakeRegistry;
@@ -26,15 +31,36 @@ use crate::registrys::{
     gen_all_pairs, 
     get_all_registerys_from_json, 
     build_metadata_map_from_changes,
+    build_metadata_map_from_events,
     set_all_metadata, 
-    update_pairs
+    update_pairs,
+    PairUpdate
 };
-use crate::router::find_best_routes_for_fixed_input_amount;
-use crate::utils::{decimal_to_u64, get_aptos_version};
+use crate::quote_cache::QuoteCache;
+use crate::ladder::{LadderRequest, LadderResponseBody, build_ladder};
+use crate::reserve_feed::{ReserveUpdate, ReserveFeedFilter};
+use crate::router::SearchConfig;
+use crate::pruning::PruningConfig;
+use crate::gas::{GasModel, RankedRoute, rank_routes_by_net_output};
+use crate::pricing::{PricingConfig, PriceCache, usd_value};
+use crate::pruning::build_pairs_by_token;
+use crate::arbitrage::{ArbitrageSnapshot, find_arbitrage};
+use crate::sizing::{SizingTarget, route_from_pair_ids};
+use crate::utils::decimal_to_u64;
+use crate::config::{Cli, RouterConfig, load_config};
+use crate::data_sources::{AptosDataSource, build_data_source};
 use crate::{
-    types::{Network, ChannelUpdateMetadata, ChannelRegistrysToWatch}
+    types::{Network, ChannelUpdateMetadata, ChannelRegistrysToWatch, UpdateMode}
 };
 use crate::aptos_transaction_watcher::aptos_watch_transactions;
+use crate::checkpoint::{
+    checkpoint_path,
+    CHECKPOINT_INTERVAL_SECS,
+    build_checkpoint,
+    read_checkpoint,
+    write_checkpoint,
+    metadata_map_from_checkpoint
+};
 
 mod pairs;
 mod manager;
@@ -43,21 +69,34 @@ mod types;
 mod registrys;
 mod router;
 mod aptos_transaction_watcher;
+mod checkpoint;
+mod data_sources;
+mod http_client;
+mod metrics;
+mod config;
+mod pruning;
+mod pricing;
+mod gas;
+mod arbitrage;
+mod sizing;
+mod quote_cache;
+mod ladder;
+mod reserve_feed;
 
-async fn initalize_router(network: &Network) -> (
+async fn initalize_router(network: &Network, mut registry_vec: Vec<Box<dyn Registry>>, source: &dyn AptosDataSource) -> (
     Vec<Box<dyn registrys::Registry>>, //registery_vec
-    HashMap<PairNames, HashMap<std::string::String, Box<dyn PairMetadata>>>, //metadata_map
+    HashMap<String, HashMap<std::string::String, Box<dyn PairMetadata>>>, //metadata_map
     Vec<Rc<RefCell<Box<(dyn Pair + 'static)>>>>, //genned_pairs
     HashMap<String, Vec<Rc<RefCell<Box<(dyn Pair + 'static)>>>>>, //pairs_by_token
 ) {
-    let mut registry_vec = get_all_registerys_from_json(network);
     println!("Registry Vec: {:?}", registry_vec.len());
 
-    let mut metadata_map: HashMap<PairNames, HashMap<String, Box<dyn PairMetadata>> > = HashMap::new();
-    metadata_map.insert(PairNames::PancakePair, HashMap::new());
-    metadata_map.insert(PairNames::LiquidswapPair, HashMap::new());
+    let mut metadata_map: HashMap<String, HashMap<String, Box<dyn PairMetadata>> > = HashMap::new();
+    for registry in &registry_vec {
+        metadata_map.insert(registry.protocol().to_string(), HashMap::new());
+    }
 
-    let gen_pairs_result = gen_all_pairs(network, &mut registry_vec).await;
+    let gen_pairs_result = gen_all_pairs(network, source, &mut registry_vec).await;
     let mut genned_pairs = gen_pairs_result.0;
     println!("Genned Pairs: {:?}", genned_pairs.len());
     let pairs_by_token = gen_pairs_result.1;
@@ -76,105 +115,790 @@ async fn root() -> &'static str {
     "Routey Is Live!"
 }
 
+async fn metrics_handler() -> String {
+    metrics::render()
+}
+
 #[derive(serde::Deserialize)]
 struct RouteRequest {
+    //Defaults to the first configured network.
+    #[serde(default)]
+    network: Option<String>,
     token_in: String,
     in_decimal: u64,
     token_out: String,
     out_decimal: u64,
     input_amount: u64,
+    //Octas per gas unit, defaults to the network's default_gas_price.
+    #[serde(default)]
+    gas_price: Option<u64>,
+}
+
+//Everything the HTTP server can ask a network's router thread, each carries the channel to answer on.
+enum ChannelRouterRequest {
+    Route(RouteRequest, mpsc::Sender<RouteResponseBody>),
+    Prices(mpsc::Sender<PricesResponseBody>),
+    Pools(mpsc::Sender<PoolsResponseBody>),
+    Arbitrage(mpsc::Sender<ArbitrageSnapshot>),
+    Batch(Vec<RouteRequest>, mpsc::Sender<BatchQuotesResponseBody>),
+    Ladder(LadderRequest, mpsc::Sender<Result<LadderResponseBody, String>>),
+    SizeRoute(SizeRouteRequest, mpsc::Sender<Result<SizeRouteResponseBody, String>>),
+    //Subscription id, Client's id for it, Quote to keep up to date, Where updates go.
+    Subscribe(u64, String, RouteRequest, tokio_mpsc::Sender<QuoteUpdate>),
+    Unsubscribe(u64),
+}
+
+//What a quotes WebSocket client sends.
+#[derive(serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum QuoteSocketMessage {
+    Subscribe {
+        id: String,
+        #[serde(flatten)]
+        request: RouteRequest,
+    },
+    Unsubscribe {
+        id: String,
+    },
+}
+
+//Sent when subscribing and whenever a pair that could change the quote is updated.
+#[derive(serde::Serialize)]
+struct QuoteUpdate {
+    id: String,
+    //Ledger version the quote reflects.
+    version: Option<u64>,
+    quote: RouteResponseBody,
+}
+
+//Lives in the router thread.
+struct QuoteSubscription {
+    id: String,
+    request: RouteRequest,
+    //Tokens on the quote's candidate routes, a pair touching any of them can change it.
+    tokens: HashSet<String>,
+    update_tx: tokio_mpsc::Sender<QuoteUpdate>,
+    //The last update didn't fit in the client's buffer, the next pass resends the quote whether or not it's affected.
+    behind: bool,
+}
+
+static NEXT_SUBSCRIPTION_ID: AtomicU64 = AtomicU64::new(0);
+
+//Subscriptions across every socket, each one is re-quoted on the router thread after updates.
+static ACTIVE_SUBSCRIPTIONS: AtomicUsize = AtomicUsize::new(0);
+const MAX_SUBSCRIPTIONS: usize = 1000;
+const MAX_SUBSCRIPTIONS_PER_SOCKET: usize = 50;
+
+//Updates waiting to go out on a socket, past this a slow client misses updates until it catches up.
+const QUOTE_UPDATE_BUFFER: usize = 64;
+
+impl QuoteSubscription {
+    fn affected_by(&self, pairs: &Vec<Rc<RefCell<Box<dyn Pair>>>>) -> bool {
+        pairs.iter().any(|pair_ref| (**pair_ref).borrow().get_token_arr().iter().any(|x| self.tokens.contains(x)))
+    }
+
+    //Returns false once the client has gone away.
+    fn send_quote(&mut self, version: Option<u64>, ranked: Vec<RankedRoute>, prices: &HashMap<String, f64>) -> bool {
+        self.tokens = ranked.iter().flat_map(|x| x.route.path.iter().cloned()).collect();
+        self.tokens.insert(self.request.token_in.clone());
+        self.tokens.insert(self.request.token_out.clone());
+        match self.update_tx.try_send(QuoteUpdate { id: self.id.clone(), version: version, quote: build_route_response(ranked.first(), prices) }) {
+            Ok(()) => {
+                self.behind = false;
+                true
+            }
+            Err(tokio_mpsc::error::TrySendError::Full(_)) => {
+                self.behind = true;
+                true
+            }
+            Err(tokio_mpsc::error::TrySendError::Closed(_)) => false
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SizeRouteRequest {
+    #[serde(default)]
+    network: Option<String>,
+    token_in: String,
+    //The route's pairs in order, as in a route response's pair_ids.
+    pair_ids: Vec<String>,
+    target: SizingTarget,
 }
 
-struct ChannelRouteRequest {
-   route_request: RouteRequest,
-   fromthread_tx: mpsc::Sender<RouteResponseBody>,
+#[derive(serde::Serialize)]
+struct SizeRouteResponseBody {
+    version: Option<u64>,
+    path: Vec<String>,
+    input_amount: u64,
+    path_amounts: Vec<u64>,
+    //Output per input, in smallest units.
+    execution_price: f64,
+    marginal_price: Option<f64>,
+    price_impact: Option<f64>,
+    //Only for cycles.
+    profit: Option<i128>,
 }
 
 #[derive(serde::Serialize)]
 struct RouteResponseBody {
     path: Vec<String>,
     path_amounts: Vec<u64>,
+    //USD value of each amount in path_amounts, None when the token has no price.
+    path_amounts_usd: Vec<Option<f64>>,
+    //Pair id of each hop.
+    pair_ids: Vec<String>,
+    //Ledger version each hop's pair was last updated at.
+    last_updated_versions: Vec<Option<u64>>,
+    gas_units: Option<u64>,
+    //In the gas token.
+    gas_cost: Option<u64>,
+    //None when the gas token has no route to token_out.
+    gas_cost_in_output: Option<u64>,
+    //Output amount minus gas_cost_in_output, what routes are ranked by.
+    net_output: Option<u64>,
+}
+
+//Most a single batch can ask for, it blocks the network's router thread while it runs.
+const MAX_BATCH_QUOTES: usize = 100;
+
+//How long a router thread waits before retrying the node at startup.
+const STARTUP_RETRY_SECS: u64 = 5;
+
+#[derive(serde::Deserialize)]
+struct BatchQuotesRequest {
+    #[serde(default)]
+    network: Option<String>,
+    //Each may leave out network, if given it has to match the batch's.
+    requests: Vec<RouteRequest>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum BatchQuoteResult {
+    Ok(RouteResponseBody),
+    Error(String),
+}
+
+#[derive(serde::Serialize)]
+struct BatchQuotesResponseBody {
+    //Every result was computed at this version.
+    version: Option<u64>,
+    //Same order as the requests.
+    results: Vec<BatchQuoteResult>,
+}
+
+#[derive(serde::Serialize)]
+struct TokenPrice {
+    usd_per_unit: f64,
+    //Only when the token's decimals are configured.
+    usd_per_token: Option<f64>,
+}
+
+#[derive(serde::Serialize)]
+struct PricesResponseBody {
+    version: Option<u64>,
+    prices: HashMap<String, TokenPrice>,
+}
+
+#[derive(serde::Serialize)]
+struct PoolInfo {
+    pair_id: String,
+    protocol: String,
+    tokens: Vec<String>,
+    reserves: Option<Vec<u64>>,
+    //None unless every token in the pool has a price.
+    tvl_usd: Option<f64>,
+    last_updated_version: Option<u64>,
+}
+
+#[derive(serde::Serialize)]
+struct PoolsResponseBody {
+    version: Option<u64>,
+    pools: Vec<PoolInfo>,
 }
 
 #[derive(Clone)]
 struct ServerState{
-    tothread_tx: mpsc::Sender<ChannelRouteRequest>,
+    //Network Name -> Channel to that network's router thread.
+    routers: Arc<HashMap<String, mpsc::Sender<ChannelRouterRequest>>>,
+    //Network Name -> Arbitrage found after each reserve update.
+    arbitrage_streams: Arc<HashMap<String, broadcast::Sender<ArbitrageSnapshot>>>,
+    //Network Name -> Every reserve change update_pairs applies.
+    reserve_streams: Arc<HashMap<String, broadcast::Sender<ReserveUpdate>>>,
+    default_network: String,
+}
+
+fn get_router(state: &ServerState, network: &str) -> Result<mpsc::Sender<ChannelRouterRequest>, (StatusCode, Json<serde_json::Value>)> {
+    match state.routers.get(network) {
+        Some(tothread_tx) => Ok(tothread_tx.clone()),
+        None => Err((StatusCode::NOT_FOUND, Json(serde_json::json!({"error": format!("Unknown network {}", network)}))))
+    }
+}
+
+//Sends a request to the network's router thread and waits for its answer.
+fn ask_router<T>(state: &ServerState, network: Option<String>, request: impl FnOnce(mpsc::Sender<T>) -> ChannelRouterRequest) -> Result<T, (StatusCode, Json<serde_json::Value>)> {
+    let network = network.unwrap_or(state.default_network.clone());
+    let tothread_tx = get_router(state, &network)?;
+
+    let (fromthread_tx, fromthread_rx) = mpsc::channel::<T>();
+    tothread_tx.send(request(fromthread_tx)).unwrap();
+
+    match fromthread_rx.recv() {
+        Ok(response) => Ok(response),
+        Err(_) => Err((StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({"error": format!("Router for {} is not running", network)}))))
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct NetworkQuery {
+    network: Option<String>,
+}
+
+async fn prices_handler(
+    State(state): State<ServerState>,
+    Query(query): Query<NetworkQuery>,
+) -> Result<Json<PricesResponseBody>, (StatusCode, Json<serde_json::Value>)> {
+    ask_router(&state, query.network, |tx| ChannelRouterRequest::Prices(tx)).map(Json)
+}
+
+async fn network_prices_handler(
+    State(state): State<ServerState>,
+    Path(network): Path<String>,
+) -> Result<Json<PricesResponseBody>, (StatusCode, Json<serde_json::Value>)> {
+    ask_router(&state, Some(network), |tx| ChannelRouterRequest::Prices(tx)).map(Json)
+}
+
+async fn pools_handler(
+    State(state): State<ServerState>,
+    Query(query): Query<NetworkQuery>,
+) -> Result<Json<PoolsResponseBody>, (StatusCode, Json<serde_json::Value>)> {
+    ask_router(&state, query.network, |tx| ChannelRouterRequest::Pools(tx)).map(Json)
+}
+
+async fn network_pools_handler(
+    State(state): State<ServerState>,
+    Path(network): Path<String>,
+) -> Result<Json<PoolsResponseBody>, (StatusCode, Json<serde_json::Value>)> {
+    ask_router(&state, Some(network), |tx| ChannelRouterRequest::Pools(tx)).map(Json)
+}
+
+async fn arbitrage_handler(
+    State(state): State<ServerState>,
+    Query(query): Query<NetworkQuery>,
+) -> Result<Json<ArbitrageSnapshot>, (StatusCode, Json<serde_json::Value>)> {
+    ask_router(&state, query.network, |tx| ChannelRouterRequest::Arbitrage(tx)).map(Json)
+}
+
+async fn network_arbitrage_handler(
+    State(state): State<ServerState>,
+    Path(network): Path<String>,
+) -> Result<Json<ArbitrageSnapshot>, (StatusCode, Json<serde_json::Value>)> {
+    ask_router(&state, Some(network), |tx| ChannelRouterRequest::Arbitrage(tx)).map(Json)
+}
+
+//Server sent events, one "arbitrage" event per search. Slow clients skip snapshots rather than fall behind.
+fn arbitrage_stream(state: &ServerState, network: Option<String>) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, Json<serde_json::Value>)> {
+    let network = network.unwrap_or(state.default_network.clone());
+    let arbitrage_rx = match state.arbitrage_streams.get(&network) {
+        Some(arbitrage_tx) => arbitrage_tx.subscribe(),
+        None => return Err((StatusCode::NOT_FOUND, Json(serde_json::json!({"error": format!("Unknown network {}", network)}))))
+    };
+    let stream = BroadcastStream::new(arbitrage_rx).filter_map(|snapshot| {
+        let snapshot = snapshot.ok()?;
+        Some(Ok(Event::default().event("arbitrage").json_data(snapshot).unwrap()))
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+async fn arbitrage_stream_handler(
+    State(state): State<ServerState>,
+    Query(query): Query<NetworkQuery>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    arbitrage_stream(&state, query.network)
+}
+
+async fn network_arbitrage_stream_handler(
+    State(state): State<ServerState>,
+    Path(network): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    arbitrage_stream(&state, Some(network))
+}
+
+async fn size_route_handler(
+    State(state): State<ServerState>,
+    Json(payload): Json<SizeRouteRequest>,
+) -> Result<Json<SizeRouteResponseBody>, (StatusCode, Json<serde_json::Value>)> {
+    let network = payload.network.clone();
+    match ask_router(&state, network, |tx| ChannelRouterRequest::SizeRoute(payload, tx))? {
+        Ok(response_body) => Ok(Json(response_body)),
+        Err(error) => Err((StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": error}))))
+    }
+}
+
+async fn network_size_route_handler(
+    State(state): State<ServerState>,
+    Path(network): Path<String>,
+    Json(mut payload): Json<SizeRouteRequest>,
+) -> Result<Json<SizeRouteResponseBody>, (StatusCode, Json<serde_json::Value>)> {
+    payload.network = Some(network);
+    size_route_handler(State(state), Json(payload)).await
+}
+
+fn build_size_route_response(version: Option<u64>, pairs: &Vec<Rc<RefCell<Box<dyn Pair>>>>, request: &SizeRouteRequest) -> Result<SizeRouteResponseBody, String> {
+    let route = route_from_pair_ids(pairs, &request.token_in, &request.pair_ids)?;
+    if let SizingTarget::MaxProfit = request.target {
+        if !route.is_cycle() {
+            return Err(String::from("max_profit needs a route that ends where it starts"));
+        }
+    }
+    let input_amount = match route.input_for_target(&request.target) {
+        Some(input_amount) => input_amount,
+        None => return Err(format!("No input amount meets {:?}", request.target))
+    };
+
+    let path_amounts = route.simulate(input_amount);
+    let output_amount = path_amounts[path_amounts.len()-1];
+    Ok(SizeRouteResponseBody {
+        version: version,
+        path: route.path.clone(),
+        input_amount: input_amount,
+        path_amounts: path_amounts,
+        execution_price: route.execution_price(input_amount),
+        marginal_price: route.marginal_price(),
+        price_impact: route.price_impact(input_amount),
+        profit: if route.is_cycle() { Some(output_amount as i128 - input_amount as i128) } else { None },
+    })
+}
+
+/*
+Server sent events mirroring pool reserves. A "snapshot" event with every matching pool comes first, then a
+"reserve_update" event per change after the snapshot's version. The feed is subscribed to before the snapshot is
+taken so nothing falls in between. A "lagged" event means updates were dropped, reconnect to get a new snapshot.
+ */
+fn reserve_stream(state: &ServerState, filter: ReserveFeedFilter) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, Json<serde_json::Value>)> {
+    let network = filter.network.clone().unwrap_or(state.default_network.clone());
+    let reserve_rx = match state.reserve_streams.get(&network) {
+        Some(reserve_tx) => reserve_tx.subscribe(),
+        None => return Err((StatusCode::NOT_FOUND, Json(serde_json::json!({"error": format!("Unknown network {}", network)}))))
+    };
+
+    let mut snapshot = ask_router(state, Some(network), |tx| ChannelRouterRequest::Pools(tx))?;
+    snapshot.pools.retain(|pool| filter.matches(&pool.protocol, &pool.tokens));
+    let snapshot_version = snapshot.version;
+    let snapshot_event = Event::default().event("snapshot").json_data(snapshot).unwrap();
+
+    let updates = BroadcastStream::new(reserve_rx).filter_map(move |update| {
+        match update {
+            Ok(update) => {
+                let already_in_snapshot = match (update.version, snapshot_version) {
+                    (Some(version), Some(snapshot_version)) => version <= snapshot_version,
+                    _ => false
+                };
+                if already_in_snapshot || !filter.matches(&update.protocol, &update.tokens) {
+                    return None;
+                }
+                Some(Ok(Event::default().event("reserve_update").json_data(update).unwrap()))
+            }
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                Some(Ok(Event::default().event("lagged").json_data(serde_json::json!({"skipped": skipped})).unwrap()))
+            }
+        }
+    });
+    Ok(Sse::new(tokio_stream::once(Ok(snapshot_event)).chain(updates)).keep_alive(KeepAlive::default()))
+}
+
+async fn reserve_stream_handler(
+    State(state): State<ServerState>,
+    Query(filter): Query<ReserveFeedFilter>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    reserve_stream(&state, filter)
+}
+
+async fn network_reserve_stream_handler(
+    State(state): State<ServerState>,
+    Path(network): Path<String>,
+    Query(mut filter): Query<ReserveFeedFilter>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    filter.network = Some(network);
+    reserve_stream(&state, filter)
+}
+
+async fn network_token_route_handler(
+    State(state): State<ServerState>,
+    Path(network): Path<String>,
+    Json(mut payload): Json<RouteRequest>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    payload.network = Some(network);
+    token_route_handler(State(state), Json(payload)).await
 }
 
 async fn token_route_handler(
     State(state): State<ServerState>,
     Json(payload): Json<RouteRequest>,
+) -> Result<Json<RouteResponseBody>, (StatusCode, Json<serde_json::Value>)> {
+    let network = payload.network.clone();
+    ask_router(&state, network, |tx| ChannelRouterRequest::Route(payload, tx)).map(Json)
+}
+
+async fn batch_quotes_handler(
+    State(state): State<ServerState>,
+    Json(payload): Json<BatchQuotesRequest>,
+) -> Result<Json<BatchQuotesResponseBody>, (StatusCode, Json<serde_json::Value>)> {
+    if payload.requests.len() > MAX_BATCH_QUOTES {
+        return Err((StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": format!("At most {} quotes per batch", MAX_BATCH_QUOTES)}))));
+    }
+    let network = payload.network.clone();
+    ask_router(&state, network, |tx| ChannelRouterRequest::Batch(payload.requests, tx)).map(Json)
+}
+
+async fn network_batch_quotes_handler(
+    State(state): State<ServerState>,
+    Path(network): Path<String>,
+    Json(mut payload): Json<BatchQuotesRequest>,
+) -> Result<Json<BatchQuotesResponseBody>, (StatusCode, Json<serde_json::Value>)> {
+    payload.network = Some(network);
+    batch_quotes_handler(State(state), Json(payload)).await
+}
+
+async fn quotes_ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<ServerState>,
+    Query(query): Query<NetworkQuery>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let network = query.network.unwrap_or(state.default_network.clone());
+    let tothread_tx = get_router(&state, &network)?;
+    Ok(ws.on_upgrade(move |socket| quote_socket(socket, network, tothread_tx)))
+}
+
+async fn network_quotes_ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<ServerState>,
+    Path(network): Path<String>,
 ) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
-    let (fromthread_tx, fromthread_rx) = mpsc::channel::<RouteResponseBody>();
+    quotes_ws_handler(ws, State(state), Query(NetworkQuery { network: Some(network) })).await
+}
 
-    state.tothread_tx.send(
-        ChannelRouteRequest{route_request: payload, fromthread_tx: fromthread_tx}
-    ).unwrap();
+/*
+Clients send {"type": "subscribe", "id": ..., <route request fields>} and {"type": "unsubscribe", "id": ...}.
+Each subscription gets its quote straight away, then again whenever the watcher updates a pair that could change it.
+ */
+async fn quote_socket(mut socket: WebSocket, network: String, tothread_tx: mpsc::Sender<ChannelRouterRequest>) {
+    let (update_tx, mut update_rx) = tokio_mpsc::channel::<QuoteUpdate>(QUOTE_UPDATE_BUFFER);
+    //Client's id -> Subscription id
+    let mut subscriptions: HashMap<String, u64> = HashMap::new();
 
-    let response_body = fromthread_rx.recv().unwrap();
+    loop {
+        tokio::select! {
+            message = socket.recv() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue
+                };
+                let reply = match serde_json::from_str::<QuoteSocketMessage>(&text) {
+                    Ok(QuoteSocketMessage::Subscribe { id, request }) => {
+                        if subscriptions.contains_key(&id) {
+                            Some(serde_json::json!({"id": id, "error": "Already subscribed"}))
+                        } else if let Err(error) = validate_batch_item(&request, &network) {
+                            Some(serde_json::json!({"id": id, "error": error}))
+                        } else if subscriptions.len() >= MAX_SUBSCRIPTIONS_PER_SOCKET {
+                            Some(serde_json::json!({"id": id, "error": format!("At most {} subscriptions per connection", MAX_SUBSCRIPTIONS_PER_SOCKET)}))
+                        } else if ACTIVE_SUBSCRIPTIONS.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| if x < MAX_SUBSCRIPTIONS { Some(x + 1) } else { None }).is_err() {
+                            Some(serde_json::json!({"id": id, "error": "Too many subscriptions, try again later"}))
+                        } else {
+                            let subscription_id = NEXT_SUBSCRIPTION_ID.fetch_add(1, Ordering::Relaxed);
+                            subscriptions.insert(id.clone(), subscription_id);
+                            let _ = tothread_tx.send(ChannelRouterRequest::Subscribe(subscription_id, id, request, update_tx.clone()));
+                            None
+                        }
+                    }
+                    Ok(QuoteSocketMessage::Unsubscribe { id }) => match subscriptions.remove(&id) {
+                        Some(subscription_id) => {
+                            ACTIVE_SUBSCRIPTIONS.fetch_sub(1, Ordering::Relaxed);
+                            let _ = tothread_tx.send(ChannelRouterRequest::Unsubscribe(subscription_id));
+                            None
+                        }
+                        None => Some(serde_json::json!({"id": id, "error": "Not subscribed"}))
+                    },
+                    Err(error) => Some(serde_json::json!({"error": format!("Invalid message: {}", error)}))
+                };
+                if let Some(reply) = reply {
+                    if socket.send(Message::Text(reply.to_string())).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            update = update_rx.recv() => {
+                let update = match update {
+                    Some(update) => update,
+                    None => break
+                };
+                //Skip updates for a subscription the client dropped while it was in flight.
+                if !subscriptions.contains_key(&update.id) {
+                    continue;
+                }
+                if socket.send(Message::Text(serde_json::to_string(&update).unwrap())).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
 
-    return Ok(Json(response_body));
+    ACTIVE_SUBSCRIPTIONS.fetch_sub(subscriptions.len(), Ordering::Relaxed);
+    for subscription_id in subscriptions.values() {
+        let _ = tothread_tx.send(ChannelRouterRequest::Unsubscribe(*subscription_id));
+    }
 }
 
-#[tokio::main]
-async fn main() {
-    // initialize tracing
-    tracing_subscriber::fmt::init();
+async fn ladder_handler(
+    State(state): State<ServerState>,
+    Json(payload): Json<LadderRequest>,
+) -> Result<Json<LadderResponseBody>, (StatusCode, Json<serde_json::Value>)> {
+    let network = payload.network.clone();
+    match ask_router(&state, network, |tx| ChannelRouterRequest::Ladder(payload, tx))? {
+        Ok(response_body) => Ok(Json(response_body)),
+        Err(error) => Err((StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": error}))))
+    }
+}
 
-    let (tothread_tx, tothread_rx) = mpsc::channel::<ChannelRouteRequest>();
-    let (tothread_updater_tx, tothread_updater_rx) = mpsc::channel::<ChannelUpdateMetadata>();
+async fn network_ladder_handler(
+    State(state): State<ServerState>,
+    Path(network): Path<String>,
+    Json(mut payload): Json<LadderRequest>,
+) -> Result<Json<LadderResponseBody>, (StatusCode, Json<serde_json::Value>)> {
+    payload.network = Some(network);
+    ladder_handler(State(state), Json(payload)).await
+}
 
-    println!("Hello, world!");
-    let network: Network;
-    match utils::get_network(String::from("aptos_mainnet")) {
-        Ok(result) => {
-            network = result;
-            println!("Name: {}, ChainID: {}, HTTP: {}", network.name, network.chain_id, network.http);
-        },
-        Err(error) => {
-            eprintln!("Error: {}", error);
-            std::process::exit(1);
+fn validate_batch_item(payload: &RouteRequest, network: &str) -> Result<(), String> {
+    if let Some(item_network) = &payload.network {
+        if item_network != network {
+            return Err(format!("Network {} doesn't match the request's network {}", item_network, network));
+        }
+    }
+    if payload.input_amount == 0 {
+        return Err(String::from("input_amount must be greater than 0"));
+    }
+    Ok(())
+}
+
+//Candidate routes ranked by output after gas, best first.
+fn quote_route(
+    payload: &RouteRequest,
+    pairs_by_token: &HashMap<String, Vec<Rc<RefCell<Box<dyn Pair>>>>>,
+    quote_cache: &mut QuoteCache,
+    gas_model: &GasModel,
+    search: &SearchConfig,
+    pruning: &PruningConfig
+) -> Vec<RankedRoute> {
+    //token_in == token_out asks for the best cycle back to the token.
+    let route_vec = quote_cache.find_routes(pairs_by_token, &payload.token_in, &payload.token_out, payload.input_amount, search, pruning);
+
+    //Gas is paid in the gas token, route it into token_out to compare against the output.
+    let gas_price = payload.gas_price.unwrap_or(gas_model.config.default_gas_price);
+    let gas_token = &gas_model.config.gas_token;
+    rank_routes_by_net_output(route_vec, gas_model, gas_price, |gas_cost| {
+        if *gas_token == payload.token_out {
+            return Some(gas_cost);
+        }
+        quote_cache.find_routes(pairs_by_token, gas_token, &payload.token_out, gas_cost, search, pruning)
+            .iter().map(|x| x.output_amount()).max()
+    })
+}
+
+fn build_route_response(ranked: Option<&RankedRoute>, prices: &HashMap<String, f64>) -> RouteResponseBody {
+    let ranked = match ranked {
+        Some(ranked) => ranked,
+        //Nothing routable, eg. one of the tokens was pruned from the graph.
+        None => return RouteResponseBody {
+            path: Vec::new(),
+            path_amounts: Vec::new(),
+            path_amounts_usd: Vec::new(),
+            pair_ids: Vec::new(),
+            last_updated_versions: Vec::new(),
+            gas_units: None,
+            gas_cost: None,
+            gas_cost_in_output: None,
+            net_output: None,
         }
+    };
+    let route = &ranked.route;
+
+    RouteResponseBody {
+        path: route.path.clone(),
+        path_amounts: route.path_amounts.clone(),
+        path_amounts_usd: route.path.iter().zip(route.path_amounts.iter()).map(|(token, amount)| usd_value(prices, token, *amount)).collect(),
+        pair_ids: route.pairs.iter().map(|x| (**x).borrow().get_pair_key().to_string()).collect(),
+        last_updated_versions: route.pairs.iter().map(|x| (**x).borrow().last_updated_version()).collect(),
+        gas_units: Some(ranked.gas_units),
+        gas_cost: Some(ranked.gas_cost),
+        gas_cost_in_output: ranked.gas_cost_in_output,
+        net_output: Some(ranked.net_output),
+    }
+}
+
+fn build_prices_response(version: Option<u64>, prices: &HashMap<String, f64>, pricing: &PricingConfig) -> PricesResponseBody {
+    let mut token_prices: HashMap<String, TokenPrice> = HashMap::new();
+    for (token, usd_per_unit) in prices {
+        token_prices.insert(token.clone(), TokenPrice {
+            usd_per_unit: *usd_per_unit,
+            usd_per_token: pricing.token_decimals.get(token).map(|decimals| usd_per_unit * 10f64.powi(*decimals)),
+        });
+    }
+    PricesResponseBody { version: version, prices: token_prices }
+}
+
+fn build_pools_response(version: Option<u64>, pairs: &Vec<Rc<RefCell<Box<dyn Pair>>>>, prices: &HashMap<String, f64>) -> PoolsResponseBody {
+    let mut pools: Vec<PoolInfo> = Vec::new();
+    for pair_rc_ref in pairs {
+        let pair = (**pair_rc_ref).borrow();
+        let reserves = pair.reserves();
+        let tvl_usd = reserves.as_ref().and_then(|reserves| {
+            pair.get_token_arr().iter().zip(reserves.iter())
+                .map(|(token, reserve)| usd_value(prices, token, *reserve))
+                .sum::<Option<f64>>()
+        });
+
+        pools.push(PoolInfo {
+            pair_id: pair.get_pair_key().to_string(),
+            protocol: pair.get_protocol().to_string(),
+            tokens: pair.get_token_arr().clone(),
+            reserves: reserves,
+            tvl_usd: tvl_usd,
+            last_updated_version: pair.last_updated_version(),
+        });
     }
+    PoolsResponseBody { version: version, pools: pools }
+}
 
-    
+//Each network gets its own router thread (registrys, pairs, watcher), requests for it are sent down the returned channel.
+fn spawn_network_router(router_network: Network, registry_vec: Vec<Box<dyn Registry>>, source: Arc<dyn AptosDataSource>, config: RouterConfig, arbitrage_tx: broadcast::Sender<ArbitrageSnapshot>, reserve_tx: broadcast::Sender<ReserveUpdate>) -> mpsc::Sender<ChannelRouterRequest> {
+    let (tothread_tx, tothread_rx) = mpsc::channel::<ChannelRouterRequest>();
+    let (tothread_updater_tx, tothread_updater_rx) = mpsc::channel::<ChannelUpdateMetadata>();
 
-    let router_network = network.clone();
     thread::spawn(move || {
         let rt = tokio::runtime::Runtime::new().unwrap();
         rt.block_on(async move {
-            let (mut registry_vec, mut metadata_map, mut genned_pairs, pairs_by_token) = initalize_router(&router_network).await;
+            let (mut registry_vec, mut metadata_map, mut genned_pairs, _) = initalize_router(&router_network, registry_vec, &*source).await;
             let registrys_to_watch = registry_vec.iter().map(|x| x.module_address().to_string()).collect::<Vec<String>>();
+            let event_types_to_watch = registry_vec.iter().flat_map(|x| x.event_type_prefixes()).collect::<Vec<String>>();
+            let swap_event_types = registry_vec.iter().map(|x| (x.swap_event_type(), x.protocol().to_string())).collect::<Vec<(String, String)>>();
+
+            let checkpoint = match read_checkpoint(&checkpoint_path(&router_network), &router_network) {
+                Ok(checkpoint) => checkpoint,
+                Err(error) => {
+                    eprintln!("Failed to read checkpoint, doing a full resync: {}", error);
+                    None
+                }
+            };
 
-            let starting_version = get_aptos_version(&router_network.http).await.unwrap();
-            //We should be running this in the loop, BUT, it is inefficiently querying data for each pair.
-            //So we're hitting a node rate limit.
+            //A checkpoint that doesn't match the current registrys is no better than none.
+            let checkpoint = checkpoint.and_then(|checkpoint| match metadata_map_from_checkpoint(&registry_vec, &checkpoint) {
+                Ok(checkpoint_metadata_map) => Some((checkpoint.version, checkpoint_metadata_map)),
+                Err(error) => {
+                    eprintln!("Failed to restore checkpoint, doing a full resync: {}", error);
+                    None
+                }
+            });
+
+            let starting_version;
+            match checkpoint {
+                Some((checkpoint_version, mut checkpoint_metadata_map)) => {
+                    //Restore pair state from the snapshot and replay everything after it.
+                    println!("Resuming from checkpoint at version {}", checkpoint_version);
+                    update_pairs(&mut genned_pairs, &mut checkpoint_metadata_map);
+                    starting_version = checkpoint_version + 1;
+                }
+                None => {
+                    //A node being down at startup shouldn't take the other networks with it, keep trying.
+                    starting_version = loop {
+                        match source.get_ledger_version().await {
+                            Ok(version) => break version,
+                            Err(error) => {
+                                eprintln!("Failed to get ledger version on {}, retrying: {}", router_network.name, error);
+                                tokio::time::sleep(tokio::time::Duration::from_secs(STARTUP_RETRY_SECS)).await;
+                            }
+                        }
+                    };
+                    //We should be running this in the loop, BUT, it is inefficiently querying data for each pair.
+                    //So we're hitting a node rate limit.
+
+                    while let Err(error) = set_all_metadata(&*source, &mut registry_vec, &mut metadata_map).await {
+                        eprintln!("Failed to get pair metadata on {}, retrying: {}", router_network.name, error);
+                        tokio::time::sleep(tokio::time::Duration::from_secs(STARTUP_RETRY_SECS)).await;
+                    }
+                    update_pairs(&mut genned_pairs, &mut metadata_map);
+                }
+            }
+
+            //Pruning depends on reserves, which we only have now.
+            let mut pairs_by_token = build_pairs_by_token(&genned_pairs, &router_network.pruning);
 
-            set_all_metadata(&network, &mut registry_vec, &mut metadata_map).await;
-            update_pairs(&mut genned_pairs, &mut metadata_map);
+            let mut price_cache = PriceCache::new();
+            let mut gas_model = GasModel::new(router_network.gas.clone());
+            let mut arbitrage = ArbitrageSnapshot { version: None, opportunities: Vec::new() };
+            let mut search_arbitrage = router_network.arbitrage.base_tokens.len() > 0;
+            let mut quote_cache = QuoteCache::new(config.quote_cache.clone(), metrics::router(&router_network.name));
+            //Subscription id -> Quote subscription
+            let mut quote_subscriptions: HashMap<u64, QuoteSubscription> = HashMap::new();
+            let mut processed_version: Option<u64> = None;
+            let mut last_checkpoint = Instant::now();
+            let mut last_reconcile = Instant::now();
 
+            let watcher_network = router_network.clone();
+            let watcher_source = source.clone();
+            let watcher_config = config.watcher.clone();
+            //Dropped along with the router thread, which stops the watcher.
+            let (_watcher_shutdown_tx, watcher_shutdown_rx) = mpsc::channel::<()>();
             thread::spawn(move || {
                 let rt = tokio::runtime::Runtime::new().unwrap();
                 rt.block_on(async move {
-                    aptos_watch_transactions(&router_network, starting_version, &tothread_updater_tx).await;
+                    aptos_watch_transactions(&watcher_network, &*watcher_source, &watcher_config, starting_version, &tothread_updater_tx, &watcher_shutdown_rx).await;
                 });
             });
 
             loop {
+                let mut pair_updates: Vec<PairUpdate> = Vec::new();
                 match tothread_updater_rx.recv_timeout(Duration::from_millis(100)) {
                     Ok(message) => {
                         match message.channel_tx {
                             Some(channel_tx) => {
                                 channel_tx.send(ChannelRegistrysToWatch{
-                                    registrys_to_watch: registrys_to_watch.clone()
+                                    registrys_to_watch: registrys_to_watch.clone(),
+                                    event_types_to_watch: event_types_to_watch.clone(),
+                                    swap_event_types: swap_event_types.clone()
                                 }).unwrap();
                             }
                             None => {}
                         }
+                        if message.resync {
+                            let mut resync_metadata_map: HashMap<String, HashMap<String, Box<dyn PairMetadata>> > = HashMap::new();
+                            match set_all_metadata(&*source, &mut registry_vec, &mut resync_metadata_map).await {
+                                Ok(_) => pair_updates.extend(update_pairs(&mut genned_pairs, &mut resync_metadata_map)),
+                                Err(error) => eprintln!("Failed to resync pair metadata: {}", error)
+                            }
+                        }
                         match message.new_metadata {
                             Some(new_metadata) => {
                                 let mut metadata_map = build_metadata_map_from_changes(&registry_vec, new_metadata);
-                                update_pairs(&mut genned_pairs, &mut metadata_map);
+                                pair_updates.extend(update_pairs(&mut genned_pairs, &mut metadata_map));
+                            }
+                            None => {}
+                        }
+                        match message.new_events {
+                            Some(new_events) => {
+                                let mut metadata_map = build_metadata_map_from_events(&registry_vec, &genned_pairs, new_events);
+                                pair_updates.extend(update_pairs(&mut genned_pairs, &mut metadata_map));
+                            }
+                            None => {}
+                        }
+                        for sample in &message.gas_samples {
+                            gas_model.observe(sample);
+                        }
+                        match message.version {
+                            Some(version) => {
+                                processed_version = Some(version);
                             }
                             None => {}
                         }
@@ -184,25 +908,132 @@ async fn main() {
                         println!("Disconnected")
                     }
                 }
+
+                //Event deltas drift from the real reserves, so every so often re-read them all.
+                if let UpdateMode::Events { reconcile_interval_secs } = router_network.update_mode {
+                    if last_reconcile.elapsed() >= Duration::from_secs(reconcile_interval_secs) {
+                        println!("Reconciling pair metadata");
+                        let mut reconcile_metadata_map: HashMap<String, HashMap<String, Box<dyn PairMetadata>> > = HashMap::new();
+                        match set_all_metadata(&*source, &mut registry_vec, &mut reconcile_metadata_map).await {
+                            Ok(_) => pair_updates.extend(update_pairs(&mut genned_pairs, &mut reconcile_metadata_map)),
+                            Err(error) => eprintln!("Failed to reconcile pair metadata: {}", error)
+                        }
+                        last_reconcile = Instant::now();
+                    }
+                }
+
+                let updated_pairs: Vec<Rc<RefCell<Box<dyn Pair>>>> = pair_updates.iter().map(|x| x.pair.clone()).collect();
+                for pair_update in &pair_updates {
+                    if let Some(reserve_update) = ReserveUpdate::from_pair_update(pair_update) {
+                        //Errors when nobody is subscribed, that's fine.
+                        let _ = reserve_tx.send(reserve_update);
+                    }
+                }
+
+                //Reserves moved, pairs may have crossed a pruning threshold either way.
+                if updated_pairs.len() > 0 {
+                    pairs_by_token = build_pairs_by_token(&genned_pairs, &router_network.pruning);
+                    price_cache.invalidate();
+                    quote_cache.invalidate_pairs(&updated_pairs);
+                    search_arbitrage = router_network.arbitrage.base_tokens.len() > 0;
+
+                    let search = config.search();
+                    let mut gone: Vec<u64> = Vec::new();
+                    for (subscription_id, subscription) in quote_subscriptions.iter_mut() {
+                        if !subscription.behind && !subscription.affected_by(&updated_pairs) {
+                            continue;
+                        }
+                        let ranked = quote_route(&subscription.request, &pairs_by_token, &mut quote_cache, &gas_model, &search, &router_network.pruning);
+                        let prices = price_cache.get(processed_version, &pairs_by_token, &router_network.pricing);
+                        if !subscription.send_quote(processed_version, ranked, prices) {
+                            gone.push(*subscription_id);
+                        }
+                    }
+                    for subscription_id in gone {
+                        quote_subscriptions.remove(&subscription_id);
+                    }
+                }
+
+                if search_arbitrage {
+                    let prices = price_cache.get(processed_version, &pairs_by_token, &router_network.pricing);
+                    arbitrage = ArbitrageSnapshot {
+                        version: processed_version,
+                        opportunities: find_arbitrage(&pairs_by_token, prices, &router_network.arbitrage)
+                    };
+                    if arbitrage.opportunities.len() > 0 {
+                        println!("Found {} arbitrage opportunities at version {:?}", arbitrage.opportunities.len(), processed_version);
+                    }
+                    //Errors when nobody is subscribed, that's fine.
+                    let _ = arbitrage_tx.send(arbitrage.clone());
+                    search_arbitrage = false;
+                }
+
+                if processed_version.is_some() && last_checkpoint.elapsed() >= Duration::from_secs(CHECKPOINT_INTERVAL_SECS) {
+                    let checkpoint_result = build_checkpoint(&router_network, processed_version.unwrap(), &registry_vec, &genned_pairs)
+                        .and_then(|checkpoint| write_checkpoint(&checkpoint_path(&router_network), &checkpoint));
+                    match checkpoint_result {
+                        Ok(_) => println!("Wrote checkpoint at version {}", processed_version.unwrap()),
+                        Err(error) => eprintln!("Failed to write checkpoint: {}", error)
+                    }
+                    last_checkpoint = Instant::now();
+                }
                 
                 match tothread_rx.recv_timeout(Duration::from_millis(500)) {
-                    Ok(message) => {
-                        let payload = message.route_request;
-                        let token_in = payload.token_in;
-                        let token_out = payload.token_out;
-                        let input_amount = payload.input_amount;
-                        let route_vec = find_best_routes_for_fixed_input_amount(&pairs_by_token, &token_in, &token_out, input_amount, 10);
-                        let best_route = &route_vec[0];
-
-                        let response_body = RouteResponseBody {
-                            path: best_route.path.clone(),
-                            path_amounts: best_route.path_amounts.clone(),
-                        };
-
-                        message.fromthread_tx.send(response_body).unwrap();
-                        
-                        println!("Path: {:?}", best_route.path);
-                        println!("Path Amounts: {:?}", best_route.path_amounts);   
+                    Ok(ChannelRouterRequest::Route(payload, fromthread_tx)) => {
+                        let ranked = quote_route(&payload, &pairs_by_token, &mut quote_cache, &gas_model, &config.search(), &router_network.pruning);
+                        let prices = price_cache.get(processed_version, &pairs_by_token, &router_network.pricing);
+
+                        let response_body = build_route_response(ranked.first(), prices);
+                        println!("Path: {:?}", response_body.path);
+                        println!("Path Amounts: {:?}", response_body.path_amounts);
+
+                        fromthread_tx.send(response_body).unwrap();
+                    },
+                    Ok(ChannelRouterRequest::Batch(payloads, fromthread_tx)) => {
+                        //All in one go so every quote sees the same reserves.
+                        let search = config.search();
+                        let mut results: Vec<Result<Vec<RankedRoute>, String>> = Vec::new();
+                        for payload in &payloads {
+                            results.push(validate_batch_item(payload, &router_network.name).map(|_| {
+                                quote_route(payload, &pairs_by_token, &mut quote_cache, &gas_model, &search, &router_network.pruning)
+                            }));
+                        }
+                        let prices = price_cache.get(processed_version, &pairs_by_token, &router_network.pricing);
+                        fromthread_tx.send(BatchQuotesResponseBody {
+                            version: processed_version,
+                            results: results.iter().map(|result| match result {
+                                Ok(ranked) => BatchQuoteResult::Ok(build_route_response(ranked.first(), prices)),
+                                Err(error) => BatchQuoteResult::Error(error.clone())
+                            }).collect()
+                        }).unwrap();
+                    },
+                    Ok(ChannelRouterRequest::Ladder(payload, fromthread_tx)) => {
+                        fromthread_tx.send(build_ladder(processed_version, &pairs_by_token, &mut quote_cache, &payload, &config.search(), &router_network.pruning)).unwrap();
+                    },
+                    Ok(ChannelRouterRequest::Subscribe(subscription_id, id, request, update_tx)) => {
+                        let ranked = quote_route(&request, &pairs_by_token, &mut quote_cache, &gas_model, &config.search(), &router_network.pruning);
+                        let prices = price_cache.get(processed_version, &pairs_by_token, &router_network.pricing);
+                        let mut subscription = QuoteSubscription { id: id, request: request, tokens: HashSet::new(), update_tx: update_tx, behind: false };
+                        if subscription.send_quote(processed_version, ranked, prices) {
+                            quote_subscriptions.insert(subscription_id, subscription);
+                        }
+                    },
+                    Ok(ChannelRouterRequest::Unsubscribe(subscription_id)) => {
+                        quote_subscriptions.remove(&subscription_id);
+                    },
+                    Ok(ChannelRouterRequest::Prices(fromthread_tx)) => {
+                        let prices = price_cache.get(processed_version, &pairs_by_token, &router_network.pricing);
+                        fromthread_tx.send(build_prices_response(processed_version, prices, &router_network.pricing)).unwrap();
+                    },
+                    Ok(ChannelRouterRequest::Pools(fromthread_tx)) => {
+                        let prices = price_cache.get(processed_version, &pairs_by_token, &router_network.pricing);
+                        fromthread_tx.send(build_pools_response(processed_version, &genned_pairs, prices)).unwrap();
+                    },
+                    Ok(ChannelRouterRequest::Arbitrage(fromthread_tx)) => {
+                        fromthread_tx.send(arbitrage.clone()).unwrap();
+                    },
+                    Ok(ChannelRouterRequest::SizeRoute(payload, fromthread_tx)) => {
+                        fromthread_tx.send(build_size_route_response(processed_version, &genned_pairs, &payload)).unwrap();
                     },
                     Err(RecvTimeoutError::Timeout) => {
                         println!("Timeout");
@@ -215,22 +1046,129 @@ async fn main() {
         });
     });
 
+    return tothread_tx;
+}
+
+#[tokio::main]
+async fn main() {
+    // initialize tracing
+    tracing_subscriber::fmt::init();
+
+    println!("Hello, world!");
+
+    let cli = Cli::parse();
+    let config = match load_config(&cli) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("Config error: {}", error);
+            std::process::exit(1);
+        }
+    };
+
+    let networks = match utils::get_networks(&config.networks_path, &config.networks) {
+        Ok(networks) => networks,
+        Err(error) => {
+            eprintln!("Config error: {}", error);
+            std::process::exit(1);
+        }
+    };
+
+    //Check everything up front so a bad config fails before any thread is started.
+    let mut to_index: Vec<(Network, Vec<Box<dyn Registry>>, Arc<dyn AptosDataSource>)> = Vec::new();
+    for network in networks {
+        let registry_vec = match get_all_registerys_from_json(&config.registrys_path, &network) {
+            Ok(registry_vec) => registry_vec,
+            Err(error) => {
+                eprintln!("Config error: {}", error);
+                std::process::exit(1);
+            }
+        };
+
+        let source = match build_data_source(&network) {
+            Ok(source) => source,
+            Err(error) => {
+                eprintln!("Config error: {}", error);
+                std::process::exit(1);
+            }
+        };
+
+        to_index.push((network, registry_vec, source));
+    }
+
+    if cli.check_config {
+        println!("Config OK, would listen on {}:{}", config.bind_address, config.port);
+        println!("Watcher: poll every {}ms, {} transactions per page. Max swaps: {}, beam width: {}, token revisits: {}", config.watcher.poll_interval_ms, config.watcher.transaction_page_limit, config.max_swaps, config.beam_width, config.allow_token_revisits);
+        for (network, registry_vec, _) in &to_index {
+            let default_str = if network.name == config.default_network() { " (default)" } else { "" };
+            println!("Network {}{}: chain id {}, {} source, fullnodes {}, {:?} updates", network.name, default_str, network.chain_id, network.data_source.describe(), network.http.join(", "), network.update_mode);
+            for registry in registry_vec {
+                println!("    {} registry at {}", registry.protocol(), registry.module_address());
+            }
+        }
+        return;
+    }
+
+    let mut routers: HashMap<String, mpsc::Sender<ChannelRouterRequest>> = HashMap::new();
+    let mut arbitrage_streams: HashMap<String, broadcast::Sender<ArbitrageSnapshot>> = HashMap::new();
+    let mut reserve_streams: HashMap<String, broadcast::Sender<ReserveUpdate>> = HashMap::new();
+    for (network, registry_vec, source) in to_index {
+        println!("Name: {}, ChainID: {}, HTTP: {}", network.name, network.chain_id, network.http.join(", "));
+        let (arbitrage_tx, _) = broadcast::channel::<ArbitrageSnapshot>(16);
+        arbitrage_streams.insert(network.name.clone(), arbitrage_tx.clone());
+        //Resyncs update every pair at once, leave room for them.
+        let (reserve_tx, _) = broadcast::channel::<ReserveUpdate>(4096);
+        reserve_streams.insert(network.name.clone(), reserve_tx.clone());
+        routers.insert(network.name.clone(), spawn_network_router(network, registry_vec, source, config.clone(), arbitrage_tx, reserve_tx));
+    }
+
     let state = ServerState{
-        tothread_tx: tothread_tx,
+        routers: Arc::new(routers),
+        arbitrage_streams: Arc::new(arbitrage_streams),
+        reserve_streams: Arc::new(reserve_streams),
+        default_network: config.default_network().to_string(),
     };
 
     // build our application with a route
     let app = Router::new()
         // `GET /` goes to `root`
         .route("/", get(root))
+        .route("/metrics", get(metrics_handler))
         .route(
             "/find_best_routes_for_fixed_input_amount",
             post(token_route_handler)
         )
+        .route(
+            "/:network/find_best_routes_for_fixed_input_amount",
+            post(network_token_route_handler)
+        )
+        .route("/prices", get(prices_handler))
+        .route("/:network/prices", get(network_prices_handler))
+        .route("/pools", get(pools_handler))
+        .route("/:network/pools", get(network_pools_handler))
+        .route("/quotes/batch", post(batch_quotes_handler))
+        .route("/:network/quotes/batch", post(network_batch_quotes_handler))
+        .route("/quotes/ws", get(quotes_ws_handler))
+        .route("/:network/quotes/ws", get(network_quotes_ws_handler))
+        .route("/ladder", post(ladder_handler))
+        .route("/:network/ladder", post(network_ladder_handler))
+        .route("/size_route", post(size_route_handler))
+        .route("/:network/size_route", post(network_size_route_handler))
+        .route("/reserves/stream", get(reserve_stream_handler))
+        .route("/:network/reserves/stream", get(network_reserve_stream_handler))
+        .route("/arbitrage", get(arbitrage_handler))
+        .route("/:network/arbitrage", get(network_arbitrage_handler))
+        .route("/arbitrage/stream", get(arbitrage_stream_handler))
+        .route("/:network/arbitrage/stream", get(network_arbitrage_stream_handler))
         .with_state(state);
 
-    // run our app with hyper, listening globally on port 3000
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    // run our app with hyper
+    let listener = match tokio::net::TcpListener::bind((config.bind_address.as_str(), config.port)).await {
+        Ok(listener) => listener,
+        Err(error) => {
+            eprintln!("Failed to bind {}:{}: {}", config.bind_address, config.port, error);
+            std::process::exit(1);
+        }
+    };
     println!("Listening on {}", listener.local_addr().unwrap());
     axum::serve(listener, app).await.unwrap();
 }
@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/*
+Process wide counters and gauges, rendered in the Prometheus text format on GET /metrics.
+Watcher and router metrics are kept per network and rendered with a network label.
+ */
+#[derive(Default)]
+pub struct WatcherMetrics {
+    pub head_version: AtomicU64,
+    pub processed_version: AtomicU64,
+    pub gaps: AtomicU64,
+    pub pruned: AtomicU64,
+    pub resyncs: AtomicU64,
+}
+
+impl WatcherMetrics {
+    pub fn set_versions(&self, head_version: u64, processed_version: u64) {
+        self.head_version.store(head_version, Ordering::Relaxed);
+        self.processed_version.store(processed_version, Ordering::Relaxed);
+    }
+
+    pub fn lag(&self) -> u64 {
+        let head_version = self.head_version.load(Ordering::Relaxed);
+        let processed_version = self.processed_version.load(Ordering::Relaxed);
+        head_version.saturating_sub(processed_version)
+    }
+}
+
+#[derive(Default)]
+pub struct RouterMetrics {
+    pub quote_cache_hits: AtomicU64,
+    pub quote_cache_misses: AtomicU64,
+    pub quote_cache_invalidations: AtomicU64,
+}
+
+//Network Name -> Watcher Metrics, BTreeMap so /metrics output is stable.
+static WATCHERS: OnceLock<Mutex<BTreeMap<String, Arc<WatcherMetrics>>>> = OnceLock::new();
+
+//Network Name -> Router Metrics
+static ROUTERS: OnceLock<Mutex<BTreeMap<String, Arc<RouterMetrics>>>> = OnceLock::new();
+
+pub fn watcher(network: &str) -> Arc<WatcherMetrics> {
+    let watchers = WATCHERS.get_or_init(|| Mutex::new(BTreeMap::new()));
+    watchers.lock().unwrap().entry(network.to_string()).or_default().clone()
+}
+
+pub fn router(network: &str) -> Arc<RouterMetrics> {
+    let routers = ROUTERS.get_or_init(|| Mutex::new(BTreeMap::new()));
+    routers.lock().unwrap().entry(network.to_string()).or_default().clone()
+}
+
+fn push_metric(out: &mut String, name: &str, metric_type: &str, help: &str, values: &Vec<(String, u64)>) {
+    out.push_str(&format!("# HELP {} {}\n", name, help));
+    out.push_str(&format!("# TYPE {} {}\n", name, metric_type));
+    for (network, value) in values {
+        out.push_str(&format!("{}{{network=\"{}\"}} {}\n", name, network, value));
+    }
+}
+
+pub fn render() -> String {
+    let watchers: Vec<(String, Arc<WatcherMetrics>)> = match WATCHERS.get() {
+        Some(watchers) => watchers.lock().unwrap().iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+        None => Vec::new()
+    };
+    let values = |f: &dyn Fn(&WatcherMetrics) -> u64| watchers.iter().map(|(network, x)| (network.clone(), f(x))).collect::<Vec<(String, u64)>>();
+
+    let mut out = String::new();
+    push_metric(&mut out, "router_watcher_head_version", "gauge", "Latest ledger version reported by the data source.", &values(&|x| x.head_version.load(Ordering::Relaxed)));
+    push_metric(&mut out, "router_watcher_processed_version", "gauge", "Last ledger version the watcher has fully processed.", &values(&|x| x.processed_version.load(Ordering::Relaxed)));
+    push_metric(&mut out, "router_watcher_lag_versions", "gauge", "Head version minus processed version.", &values(&|x| x.lag()));
+    push_metric(&mut out, "router_watcher_gaps_total", "counter", "Transaction pages that were not contiguous with the last processed version.", &values(&|x| x.gaps.load(Ordering::Relaxed)));
+    push_metric(&mut out, "router_watcher_pruned_total", "counter", "Transaction requests that hit pruned history.", &values(&|x| x.pruned.load(Ordering::Relaxed)));
+    push_metric(&mut out, "router_watcher_resyncs_total", "counter", "Full resource resyncs triggered by the watcher.", &values(&|x| x.resyncs.load(Ordering::Relaxed)));
+
+    let routers: Vec<(String, Arc<RouterMetrics>)> = match ROUTERS.get() {
+        Some(routers) => routers.lock().unwrap().iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+        None => Vec::new()
+    };
+    let router_values = |f: &dyn Fn(&RouterMetrics) -> u64| routers.iter().map(|(network, x)| (network.clone(), f(x))).collect::<Vec<(String, u64)>>();
+    push_metric(&mut out, "router_quote_cache_hits_total", "counter", "Route requests answered from the quote cache.", &router_values(&|x| x.quote_cache_hits.load(Ordering::Relaxed)));
+    push_metric(&mut out, "router_quote_cache_misses_total", "counter", "Route requests that had to search.", &router_values(&|x| x.quote_cache_misses.load(Ordering::Relaxed)));
+    push_metric(&mut out, "router_quote_cache_invalidations_total", "counter", "Quote cache entries dropped because a pair they depend on was updated.", &router_values(&|x| x.quote_cache_invalidations.load(Ordering::Relaxed)));
+    out
+}
@@ -1,33 +1,99 @@
 use std::any::Any;
+use std::fmt;
 
 use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use anyhow::{Result, anyhow};
 use erased_serde::serialize_trait_object;
 
+use crate::registrys::protocol_constructors;
+
 pub mod pancake_pair;
 pub mod liquidswap_pair;
 
-pub trait PairMetadata: Send + Sync {
+pub trait PairMetadata: erased_serde::Serialize + Send + Sync {
     fn as_any(&self) -> &dyn Any;
+    //Ledger version this metadata reflects, None when it isn't known.
+    fn last_updated_version(&self) -> Option<u64>;
 }
 
+serialize_trait_object!(PairMetadata);
+
 pub trait Pair: Send + Sync {
     fn output_amount(&self, input_amount: u64, token_in: &String, token_out: &String) -> u64;
     fn get_descriptor(&self) -> Box<dyn Descriptor>;
     fn get_protocol(&self) -> &str;
+    fn get_network(&self) -> &str;
+    //pair_id().key(), kept on the pair since it's compared on every hop while routing.
     fn get_pair_key(&self) -> &str;
+    fn pair_id(&self) -> PairId;
     fn get_token_arr(&self) -> &Vec<String>;
+    //Reserves in token_arr order, None until the pair has metadata.
+    fn reserves(&self) -> Option<Vec<u64>>;
+    //Key used by the registrys for this pairs entry in the metadata map, the pair id key.
+    fn metadata_key(&self) -> String {
+        self.get_pair_key().to_string()
+    }
+    //None when the pair has not been given any metadata yet.
+    fn get_metadata(&self) -> Option<Box<dyn PairMetadata>>;
+    //Replaces this pairs metadata, errors if it isn't this protocols metadata type.
+    fn apply_metadata(&mut self, metadata: &dyn PairMetadata) -> Result<()>;
+    fn last_updated_version(&self) -> Option<u64>;
     fn as_any(&self) -> &dyn Any;
     fn as_any_mut(&mut self) -> &mut dyn Any;
 }
 
-pub trait Descriptor: erased_serde::Serialize {}
+/*
+Canonical identity of a pool: which protocol, the address it's deployed at, its tokens (sorted, so 
+X/Y order never matters) and the variant for protocols with several pools per token pair, eg. 
+Liquidswap's Stable and Uncorrelated curves. Used for pair keys, dedup, metadata lookup and API output.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct PairId {
+    pub protocol: String,
+    pub deployment: String,
+    pub tokens: Vec<String>,
+    pub variant: Option<String>
+}
 
-serialize_trait_object!(Descriptor);
+impl PairId {
+    pub fn new(protocol: &str, deployment: &str, tokens: Vec<String>, variant: Option<&str>) -> PairId {
+        let mut tokens = tokens;
+        tokens.sort();
+        PairId {
+            protocol: protocol.to_string(),
+            deployment: deployment.to_string(),
+            tokens: tokens,
+            variant: variant.map(|x| x.to_string())
+        }
+    }
 
-#[derive(Serialize, Deserialize, Clone, Eq, Hash, PartialEq)]
-pub enum PairNames {
-    PancakePair,
-    LiquidswapPair
+    //Token types contain "::" so fields are separated with '|'.
+    pub fn key(&self) -> String {
+        let mut key = format!("{}|{}|{}", self.protocol, self.deployment, self.tokens.join(","));
+        if let Some(variant) = &self.variant {
+            key.push('|');
+            key.push_str(variant);
+        }
+        key
+    }
 }
 
+impl fmt::Display for PairId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.key())
+    }
+}
 
+pub trait Descriptor: erased_serde::Serialize {}
+
+serialize_trait_object!(Descriptor);
+
+//Rebuilds a pair from a descriptor written by get_descriptor, with the constructor registered for its protocol.
+pub fn pair_from_value_descriptor(descriptor: Value) -> Result<Box<dyn Pair>> {
+    let protocol = descriptor.get("protocol").and_then(|x| x.as_str()).unwrap_or("").to_string();
+    match protocol_constructors().get(protocol.as_str()) {
+        Some(constructors) => (constructors.pair)(descriptor),
+        None => Err(anyhow!("Unknown pair protocol in descriptor: {}", protocol))
+    }
+}
@@ -1,19 +1,24 @@
 use std::{any::Any};
 
-use super::{Pair, PairMetadata, PairNames, Descriptor};
+use super::{Pair, PairId, PairMetadata, Descriptor};
 
 use serde::{Serialize, Deserialize};
 use serde_json::Value;
+use anyhow::{Result, anyhow};
 use ethers_core::types::U256;
 
 #[derive(Serialize, Deserialize)]
 pub struct LiquidswapDescriptor {
     pub network: String,
     pub protocol: String,
-    pub pair_name: PairNames,
     pub pool_addr: String,
     pub token_arr: Vec<String>,
     pub router_pair_addr: String,
+    pub curve_type: CurveType,
+    pub x_scale: u64,
+    pub y_scale: u64,
+    pub fee: u64,
+    pub dao_fee: u64,
 }
 
 impl Descriptor for LiquidswapDescriptor {}
@@ -24,21 +29,40 @@ pub enum CurveType {
     Stable
 }
 
+impl CurveType {
+    //Accepts either the bare name or the full curve type, eg. 0x163d...::curves::Stable
+    pub fn from_type_str(type_str: &str) -> CurveType {
+        if type_str.ends_with("Uncorrelated") { CurveType::Uncorrelated } else { CurveType::Stable }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CurveType::Uncorrelated => "Uncorrelated",
+            CurveType::Stable => "Stable"
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct LiquidswapMetadata {
-    pub reserves: Vec<u64>
+    pub reserves: Vec<u64>,
+    #[serde(default)]
+    pub last_updated_version: Option<u64>
 }
 impl PairMetadata for LiquidswapMetadata {
     fn as_any(&self) -> &dyn Any {
         self
     }
+
+    fn last_updated_version(&self) -> Option<u64> {
+        self.last_updated_version
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct LiquidswapPair {
     pub network: String,
     pub protocol: String,
-    pub pair_name: PairNames,
     pub pair_key: String,
     pub pool_addr: String,
     pub token_arr: Vec<String>,
@@ -198,11 +222,14 @@ impl Pair for LiquidswapPair {
             LiquidswapDescriptor {
                 network: self.network.clone(),
                 protocol: self.protocol.clone(),
-                pair_name: self.pair_name.clone(),
                 pool_addr: self.pool_addr.clone(),
                 token_arr: self.token_arr.clone(),
-                router_pair_addr: self.router_pair_addr.clone()
-
+                router_pair_addr: self.router_pair_addr.clone(),
+                curve_type: self.curve_type.clone(),
+                x_scale: self.x_scale,
+                y_scale: self.y_scale,
+                fee: self.fee,
+                dao_fee: self.dao_fee
             }
         )
     }
@@ -211,14 +238,44 @@ impl Pair for LiquidswapPair {
         return &self.protocol;
     }
 
+    fn get_network(&self) -> &str {
+        return &self.network;
+    }
+
     fn get_token_arr(&self) -> &Vec<String> {
         return &self.token_arr;
     }
 
+    fn reserves(&self) -> Option<Vec<u64>> {
+        return Some(self.metadata.reserves.clone());
+    }
+
     fn get_pair_key(&self) -> &str {
         return &self.pair_key;
     }
 
+    fn pair_id(&self) -> PairId {
+        return liquidswap_pair_id(&self.pool_addr, &self.token_arr[0], &self.token_arr[1], &self.curve_type);
+    }
+
+    fn get_metadata(&self) -> Option<Box<dyn PairMetadata>> {
+        return Some(Box::new(self.metadata.clone()));
+    }
+
+    fn apply_metadata(&mut self, metadata: &dyn PairMetadata) -> Result<()> {
+        match metadata.as_any().downcast_ref::<LiquidswapMetadata>() {
+            Some(metadata) => {
+                self.metadata = metadata.clone();
+                Ok(())
+            }
+            None => Err(anyhow!("Expected LiquidswapMetadata for pair {}", self.pair_key))
+        }
+    }
+
+    fn last_updated_version(&self) -> Option<u64> {
+        return self.metadata.last_updated_version;
+    }
+
     fn as_any(&self) -> &dyn Any {
         self
     }
@@ -226,4 +283,82 @@ impl Pair for LiquidswapPair {
     fn as_any_mut(&mut self) -> &mut dyn Any {
         self
     }
-}
\ No newline at end of file
+}
+
+pub fn liquidswap_pair_id(pool_addr: &str, token_x: &str, token_y: &str, curve_type: &CurveType) -> PairId {
+    return PairId::new("liquidswap_constant_product", pool_addr, vec![token_x.to_string(), token_y.to_string()], Some(curve_type.as_str()));
+}
+
+pub fn liquidswap_from_value_descriptor(descriptor: Value) -> Result<LiquidswapPair> {
+    let descriptor: LiquidswapDescriptor = serde_json::from_value(descriptor)
+        .map_err(|error| anyhow!("Invalid Liquidswap descriptor: {}", error))?;
+    if descriptor.token_arr.len() != 2 {
+        return Err(anyhow!("Liquidswap descriptor has {} tokens, expected 2", descriptor.token_arr.len()));
+    }
+    let pair_key = liquidswap_pair_id(&descriptor.pool_addr, &descriptor.token_arr[0], &descriptor.token_arr[1], &descriptor.curve_type).key();
+
+    return Ok(LiquidswapPair {
+        network: descriptor.network,
+        protocol: descriptor.protocol,
+        pair_key: pair_key,
+        pool_addr: descriptor.pool_addr,
+        token_arr: descriptor.token_arr,
+        router_pair_addr: descriptor.router_pair_addr,
+        curve_type: descriptor.curve_type,
+        x_scale: descriptor.x_scale,
+        y_scale: descriptor.y_scale,
+        fee: descriptor.fee,
+        dao_fee: descriptor.dao_fee,
+        //Reserves are filled in by the registry or checkpoint before the pair is routed through.
+        metadata: LiquidswapMetadata { reserves: vec![0, 0], last_updated_version: None }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell};
+
+    use super::{LiquidswapPair, LiquidswapMetadata, CurveType, liquidswap_pair_id};
+    use crate::pairs::Pair;
+    use crate::pruning::{PruningConfig, build_pairs_by_token};
+
+    const POOL: &str = "0xabc";
+    const TOKEN_X: &str = "0x1::aptos_coin::AptosCoin";
+    const TOKEN_Y: &str = "0x2::usdc::USDC";
+
+    fn pair(curve_type: CurveType) -> LiquidswapPair {
+        LiquidswapPair {
+            network: String::from("mainnet"),
+            protocol: String::from("liquidswap_constant_product"),
+            pair_key: liquidswap_pair_id(POOL, TOKEN_X, TOKEN_Y, &curve_type).key(),
+            pool_addr: String::from(POOL),
+            token_arr: vec![TOKEN_X.to_string(), TOKEN_Y.to_string()],
+            router_pair_addr: String::new(),
+            curve_type: curve_type,
+            x_scale: 100000000,
+            y_scale: 1000000,
+            fee: 30,
+            dao_fee: 33,
+            metadata: LiquidswapMetadata { reserves: vec![1000000000, 5000000], last_updated_version: None }
+        }
+    }
+
+    #[test]
+    fn stable_and_uncorrelated_pools_are_separate_pairs() {
+        let stable = pair(CurveType::Stable);
+        let uncorrelated = pair(CurveType::Uncorrelated);
+        assert_ne!(stable.get_pair_key(), uncorrelated.get_pair_key());
+
+        let pairs: Vec<Rc<RefCell<Box<dyn Pair>>>> = vec![
+            Rc::new(RefCell::new(Box::new(stable))),
+            Rc::new(RefCell::new(Box::new(uncorrelated)))
+        ];
+        let pairs_by_token = build_pairs_by_token(&pairs, &PruningConfig::default());
+        for token in [TOKEN_X, TOKEN_Y] {
+            let keys: Vec<String> = pairs_by_token[token].iter().map(|x| (**x).borrow().get_pair_key().to_string()).collect();
+            assert_eq!(keys.len(), 2);
+            assert!(keys.contains(&liquidswap_pair_id(POOL, TOKEN_X, TOKEN_Y, &CurveType::Stable).key()));
+            assert!(keys.contains(&liquidswap_pair_id(POOL, TOKEN_X, TOKEN_Y, &CurveType::Uncorrelated).key()));
+        }
+    }
+}
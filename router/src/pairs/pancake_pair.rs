@@ -1,16 +1,16 @@
 use std::{any::Any};
 
-use super::{Pair, PairMetadata, PairNames, Descriptor};
+use super::{Pair, PairId, PairMetadata, Descriptor};
 
 use serde::{Serialize, Deserialize};
 use serde_json::Value;
+use anyhow::{Result, anyhow};
 
 
 #[derive(Serialize, Deserialize)]
 pub struct PancakeDescriptor {
     pub network: String,
     pub protocol: String,
-    pub pair_name: PairNames,
     pub pool_addr: String,
     pub token_arr: Vec<String>,
     pub router_pair_addr: String,
@@ -22,18 +22,23 @@ impl Descriptor for PancakeDescriptor {}
 pub struct PancakeMetadata {
     pub reserves: Option<Vec<u64>>,
     // pub last_k: Option<u128>
+    #[serde(default)]
+    pub last_updated_version: Option<u64>
 }
 impl PairMetadata for PancakeMetadata {
     fn as_any(&self) -> &dyn Any {
         self
     }
+
+    fn last_updated_version(&self) -> Option<u64> {
+        self.last_updated_version
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct PancakePair {
     pub network: String,
     pub protocol: String,
-    pub pair_name: PairNames,
     pub pair_key: String,
     pub pool_addr: String,
     pub token_arr: Vec<String>,
@@ -60,7 +65,6 @@ impl Pair for PancakePair {
             PancakeDescriptor {
                 network: self.network.clone(),
                 protocol: self.protocol.clone(),
-                pair_name: self.pair_name.clone(),
                 pool_addr: self.pool_addr.clone(),
                 token_arr: self.token_arr.clone(),
                 router_pair_addr: self.router_pair_addr.clone()
@@ -73,14 +77,47 @@ impl Pair for PancakePair {
         return &self.protocol;
     }
 
+    fn get_network(&self) -> &str {
+        return &self.network;
+    }
+
     fn get_token_arr(&self) -> &Vec<String> {
         return &self.token_arr;
     }
 
+    fn reserves(&self) -> Option<Vec<u64>> {
+        return self.metadata.reserves.clone();
+    }
+
     fn get_pair_key(&self) -> &str {
         return &self.pair_key;
     }
 
+    fn pair_id(&self) -> PairId {
+        return pancake_pair_id(&self.pool_addr, &self.token_arr[0], &self.token_arr[1]);
+    }
+
+    fn get_metadata(&self) -> Option<Box<dyn PairMetadata>> {
+        if self.metadata.reserves.is_none() {
+            return None;
+        }
+        return Some(Box::new(self.metadata.clone()));
+    }
+
+    fn apply_metadata(&mut self, metadata: &dyn PairMetadata) -> Result<()> {
+        match metadata.as_any().downcast_ref::<PancakeMetadata>() {
+            Some(metadata) => {
+                self.metadata = metadata.clone();
+                Ok(())
+            }
+            None => Err(anyhow!("Expected PancakeMetadata for pair {}", self.pair_key))
+        }
+    }
+
+    fn last_updated_version(&self) -> Option<u64> {
+        return self.metadata.last_updated_version;
+    }
+
     fn as_any(&self) -> &dyn Any {
         self
     }
@@ -90,29 +127,32 @@ impl Pair for PancakePair {
     }
 }
 
-pub fn pancake_from_value_descriptor(descriptor: Value) -> PancakePair {
-    let token_val_arr: Vec<Value> = descriptor.get("token_arr").unwrap().as_array().unwrap().clone();
-    let mut token_arr: Vec<String> = Vec::new();
+pub fn pancake_pair_id(module_address: &str, token_x: &str, token_y: &str) -> PairId {
+    return PairId::new("pancake", module_address, vec![token_x.to_string(), token_y.to_string()], None);
+}
 
-    for val in token_val_arr {
-        let token = val.as_str().unwrap().to_string();
-        token_arr.push(token);
+pub fn pancake_from_value_descriptor(descriptor: Value) -> Result<PancakePair> {
+    let field = |name: &str| descriptor.get(name).and_then(|x| x.as_str()).map(|x| x.to_string())
+        .ok_or(anyhow!("Pancake descriptor is missing {}", name));
+
+    let token_arr: Vec<String> = match descriptor.get("token_arr").and_then(|x| x.as_array()) {
+        Some(token_val_arr) => token_val_arr.iter().filter_map(|x| x.as_str()).map(|x| x.to_string()).collect(),
+        None => return Err(anyhow!("Pancake descriptor is missing token_arr"))
+    };
+    if token_arr.len() != 2 {
+        return Err(anyhow!("Pancake descriptor has {} tokens, expected 2", token_arr.len()));
     }
 
-    let network = descriptor.get("network").unwrap().clone();
-    let protocol = descriptor.get("protocol").unwrap().clone();
-    let pool_addr = descriptor.get("pool_addr").unwrap().clone();
-    let router_addr = descriptor.get("router_pair_addr").unwrap().clone();
-    let pair_key = format!("{}{}{}", pool_addr, token_arr[0], token_arr[1]);
+    let pool_addr = field("pool_addr")?;
+    let pair_key = pancake_pair_id(&pool_addr, &token_arr[0], &token_arr[1]).key();
 
-    return PancakePair {
-            network: network.as_str().unwrap().to_string(),
-            protocol: protocol.as_str().unwrap().to_string(),
-            pair_name: PairNames::PancakePair,
+    return Ok(PancakePair {
+            network: field("network")?,
+            protocol: field("protocol")?,
             pair_key: pair_key,
-            pool_addr: pool_addr.as_str().unwrap().to_string(),
+            pool_addr: pool_addr,
             token_arr: token_arr,
-            router_pair_addr: router_addr.as_str().unwrap().to_string(),
-            metadata: PancakeMetadata { reserves: None }
-        }
+            router_pair_addr: field("router_pair_addr")?,
+            metadata: PancakeMetadata { reserves: None, last_updated_version: None }
+        })
 }
\ No newline at end of file
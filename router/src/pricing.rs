@@ -0,0 +1,145 @@
+use std::{collections::HashMap, rc::Rc, cell::RefCell};
+
+use serde::{Serialize, Deserialize};
+
+use crate::pairs::Pair;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Stablecoin {
+    pub token: String,
+    pub decimals: i32
+}
+
+fn default_max_hops() -> u8 { 3 }
+
+fn default_probe_divisor() -> u64 { 10000 }
+
+/*
+Prices are USD per smallest unit of a token, so they work without knowing every token's decimals.
+Stablecoins are pinned at $1, every other token is priced by routing a small amount through each
+pool that connects it to an already priced token, working outwards from the stablecoins one hop at a time.
+ */
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PricingConfig {
+    #[serde(default)]
+    pub stablecoins: Vec<Stablecoin>,
+    //How many hops away from a stablecoin a token can be and still get a price.
+    #[serde(default = "default_max_hops")]
+    pub max_hops: u8,
+    //The probe amount is the pool's reserve of the token divided by this, small enough to keep price impact negligible.
+    #[serde(default = "default_probe_divisor")]
+    pub probe_divisor: u64,
+    //Pools with less USD than this on their priced side don't contribute to a price.
+    #[serde(default)]
+    pub min_liquidity_usd: f64,
+    //Only used to show per token prices, Token -> Decimals.
+    #[serde(default)]
+    pub token_decimals: HashMap<String, i32>
+}
+
+impl Default for PricingConfig {
+    fn default() -> Self {
+        PricingConfig {
+            stablecoins: Vec::new(),
+            max_hops: default_max_hops(),
+            probe_divisor: default_probe_divisor(),
+            min_liquidity_usd: 0.0,
+            token_decimals: HashMap::new()
+        }
+    }
+}
+
+pub fn compute_prices(pairs_by_token: &HashMap<String, Vec<Rc<RefCell<Box<dyn Pair>>>>>, config: &PricingConfig) -> HashMap<String, f64> {
+    //Token -> USD per smallest unit
+    let mut prices: HashMap<String, f64> = HashMap::new();
+    for stablecoin in &config.stablecoins {
+        prices.insert(stablecoin.token.clone(), 10f64.powi(-stablecoin.decimals));
+    }
+
+    let mut frontier: Vec<String> = prices.keys().cloned().collect();
+    for _ in 0..config.max_hops {
+        //Token -> (Sum of weighted estimates, Sum of weights). Only prices from earlier hops are used so the order we visit pools in doesn't matter.
+        let mut estimates: HashMap<String, (f64, f64)> = HashMap::new();
+
+        for priced_token in &frontier {
+            let price_priced = prices[priced_token];
+            let pairs = match pairs_by_token.get(priced_token) {
+                Some(pairs) => pairs,
+                None => continue
+            };
+
+            for pair_ref in pairs {
+                let pair = (**pair_ref).borrow();
+                let reserves = match pair.reserves() {
+                    Some(reserves) => reserves,
+                    None => continue
+                };
+                let token_arr = pair.get_token_arr();
+                let priced_index = token_arr.iter().position(|x| x == priced_token).unwrap();
+
+                for (i, token) in token_arr.iter().enumerate() {
+                    if prices.contains_key(token) {
+                        continue;
+                    }
+
+                    let weight = reserves[priced_index] as f64 * price_priced;
+                    if weight <= 0.0 || weight < config.min_liquidity_usd {
+                        continue;
+                    }
+
+                    let probe_amount = std::cmp::max(1, reserves[i] / config.probe_divisor);
+                    let output_amount = pair.output_amount(probe_amount, token, priced_token);
+                    if output_amount == 0 {
+                        continue;
+                    }
+
+                    let estimate = output_amount as f64 * price_priced / probe_amount as f64;
+                    let entry = estimates.entry(token.clone()).or_insert((0.0, 0.0));
+                    entry.0 += estimate * weight;
+                    entry.1 += weight;
+                }
+            }
+        }
+
+        if estimates.len() == 0 {
+            break;
+        }
+
+        frontier = Vec::new();
+        for (token, (weighted_sum, weight_sum)) in estimates {
+            prices.insert(token.clone(), weighted_sum / weight_sum);
+            frontier.push(token);
+        }
+    }
+
+    prices
+}
+
+pub fn usd_value(prices: &HashMap<String, f64>, token: &String, amount: u64) -> Option<f64> {
+    prices.get(token).map(|price| amount as f64 * price)
+}
+
+//Prices only change when reserves do, so they're recomputed at most once per processed ledger version.
+pub struct PriceCache {
+    pub version: Option<u64>,
+    pub prices: Option<HashMap<String, f64>>
+}
+
+impl PriceCache {
+    pub fn new() -> PriceCache {
+        PriceCache { version: None, prices: None }
+    }
+
+    pub fn get(&mut self, version: Option<u64>, pairs_by_token: &HashMap<String, Vec<Rc<RefCell<Box<dyn Pair>>>>>, config: &PricingConfig) -> &HashMap<String, f64> {
+        if self.prices.is_none() || self.version != version {
+            self.prices = Some(compute_prices(pairs_by_token, config));
+            self.version = version;
+        }
+        self.prices.as_ref().unwrap()
+    }
+
+    //The graph was rebuilt without a new version, eg. a resync.
+    pub fn invalidate(&mut self) {
+        self.prices = None;
+    }
+}
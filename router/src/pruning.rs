@@ -0,0 +1,99 @@
+use std::{collections::HashMap, rc::Rc, cell::RefCell};
+
+use serde::{Serialize, Deserialize};
+
+use crate::pairs::Pair;
+
+/*
+Which pairs make it into pairs_by_token. Every pair is still tracked and updated, pruning only
+decides what the router searches through, and is re-evaluated whenever reserves change.
+Reserves are in the token's smallest unit, same as everything else in the router.
+ */
+#[derive(Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct PruningConfig {
+    //Both reserves of a pair must be at least this.
+    pub min_reserve: u64,
+    //Pairs with min_tvl_token on one side need 2 * that reserve >= min_tvl_amount. Pairs without it aren't TVL checked.
+    pub min_tvl_token: Option<String>,
+    pub min_tvl_amount: u64,
+    //When set, only pairs where both tokens are listed are routed through.
+    pub allow_tokens: Option<Vec<String>>,
+    pub deny_tokens: Vec<String>,
+    //Per route check, skip a hop when its input is more than this share of the pair's input reserve.
+    pub max_input_reserve_share: Option<f64>,
+}
+
+impl PruningConfig {
+    pub fn pair_allowed(&self, pair: &dyn Pair) -> bool {
+        let token_arr = pair.get_token_arr();
+
+        if token_arr.iter().any(|x| self.deny_tokens.contains(x)) {
+            return false;
+        }
+        if let Some(allow_tokens) = &self.allow_tokens {
+            if !token_arr.iter().all(|x| allow_tokens.contains(x)) {
+                return false;
+            }
+        }
+
+        //No reserves yet or an empty side, nothing can be routed through it.
+        let reserves = match pair.reserves() {
+            Some(reserves) if reserves.iter().all(|x| *x > 0) => reserves,
+            _ => return false
+        };
+
+        if reserves.iter().any(|x| *x < self.min_reserve) {
+            return false;
+        }
+
+        if let Some(min_tvl_token) = &self.min_tvl_token {
+            if let Some(index) = token_arr.iter().position(|x| x == min_tvl_token) {
+                if (reserves[index] as u128) * 2 < self.min_tvl_amount as u128 {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    pub fn hop_allowed(&self, pair: &dyn Pair, token_in: &String, amount_in: u64) -> bool {
+        let max_share = match self.max_input_reserve_share {
+            Some(max_share) => max_share,
+            None => return true
+        };
+        let in_index = match pair.get_token_arr().iter().position(|x| x == token_in) {
+            Some(in_index) => in_index,
+            None => return false
+        };
+        match pair.reserves() {
+            Some(reserves) => (amount_in as f64) <= (reserves[in_index] as f64) * max_share,
+            None => false
+        }
+    }
+}
+
+pub fn build_pairs_by_token(pairs: &Vec<Rc<RefCell<Box<dyn Pair>>>>, pruning: &PruningConfig) -> HashMap<String, Vec<Rc<RefCell<Box<dyn Pair>>>>> {
+    let mut pairs_by_token: HashMap<String, Vec<Rc<RefCell<Box<dyn Pair>>>> > = HashMap::new();
+    let mut pruned = 0;
+
+    for pair_rc_ref in pairs {
+        let pair = (**pair_rc_ref).borrow();
+        if !pruning.pair_allowed(&**pair) {
+            pruned += 1;
+            continue;
+        }
+
+        for token in pair.get_token_arr() {
+            if !pairs_by_token.contains_key(token) {
+                pairs_by_token.insert(token.clone(), Vec::new());
+            }
+            let pair_vec = pairs_by_token.get_mut(token).unwrap();
+            pair_vec.push(pair_rc_ref.clone());
+        }
+    }
+
+    println!("Routing through {} of {} pairs ({} pruned)", pairs.len() - pruned, pairs.len(), pruned);
+    pairs_by_token
+}
@@ -0,0 +1,252 @@
+use std::{collections::{HashMap, HashSet}, rc::Rc, cell::RefCell, sync::Arc};
+use std::sync::atomic::Ordering;
+
+use serde::Deserialize;
+
+use crate::pairs::Pair;
+use crate::pruning::PruningConfig;
+use crate::router::{Route, SearchConfig, find_best_routes_for_fixed_input_amount, find_cyclic_routes};
+use crate::metrics::RouterMetrics;
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuoteCacheConfig {
+    pub enabled: bool,
+    //Amounts within a factor of 2^(1/buckets_per_doubling) of each other share routes.
+    pub buckets_per_doubling: u32,
+    //Past this the whole cache is dropped, it refills from whatever is being asked for.
+    pub max_entries: usize,
+}
+
+impl Default for QuoteCacheConfig {
+    fn default() -> Self {
+        QuoteCacheConfig {
+            enabled: true,
+            buckets_per_doubling: 4,
+            max_entries: 10000,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct QuoteKey {
+    pub token_in: String,
+    pub token_out: String,
+    pub amount_bucket: u32,
+}
+
+struct QuoteEntry {
+    routes: Vec<Rc<Route>>,
+    tokens: HashSet<String>,
+}
+
+/*
+Caches which routes a search found, not their amounts: a hit re-simulates the cached routes at the
+requested amount, so nearby amounts share an entry and the numbers are always exact for current reserves.
+An entry is dropped when a pair on one of its routes, or any pair touching one of its routes' tokens, is updated.
+Search settings are fixed per process so they aren't part of the key, gas ranking is done after the cache.
+ */
+pub struct QuoteCache {
+    config: QuoteCacheConfig,
+    entries: HashMap<QuoteKey, QuoteEntry>,
+    //Token -> Keys of entries with a route through it
+    by_token: HashMap<String, HashSet<QuoteKey>>,
+    metrics: Arc<RouterMetrics>,
+}
+
+impl QuoteCache {
+    pub fn new(config: QuoteCacheConfig, metrics: Arc<RouterMetrics>) -> QuoteCache {
+        QuoteCache { config: config, entries: HashMap::new(), by_token: HashMap::new(), metrics: metrics }
+    }
+
+    fn key(&self, token_in: &String, token_out: &String, input_amount: u64) -> QuoteKey {
+        let amount_bucket = ((input_amount.max(1) as f64).log2() * self.config.buckets_per_doubling as f64).floor() as u32;
+        QuoteKey { token_in: token_in.clone(), token_out: token_out.clone(), amount_bucket: amount_bucket }
+    }
+
+    //Routes from token_in to token_out for input_amount, best first. token_in == token_out gives cycles.
+    pub fn find_routes(
+        &mut self,
+        pairs_by_token: &HashMap<String, Vec<Rc<RefCell<Box<dyn Pair>>>>>,
+        token_in: &String,
+        token_out: &String,
+        input_amount: u64,
+        search: &SearchConfig,
+        pruning: &PruningConfig
+    ) -> Vec<Rc<Route>> {
+        let key = self.key(token_in, token_out, input_amount);
+        if self.config.enabled {
+            if let Some(entry) = self.entries.get(&key) {
+                self.metrics.quote_cache_hits.fetch_add(1, Ordering::Relaxed);
+                let mut routes: Vec<Rc<Route>> = entry.routes.iter().map(|route| Rc::new(Route {
+                    pairs: route.pairs.clone(),
+                    path: route.path.clone(),
+                    path_amounts: route.simulate(input_amount)
+                })).filter(|route| route.output_amount() > 0).collect();
+                routes.sort_by(|a, b| b.output_amount().cmp(&a.output_amount()).then(a.pairs.len().cmp(&b.pairs.len())));
+                return routes;
+            }
+            self.metrics.quote_cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let routes = if token_in == token_out {
+            find_cyclic_routes(pairs_by_token, token_in, input_amount, search, pruning)
+        } else {
+            find_best_routes_for_fixed_input_amount(pairs_by_token, token_in, token_out, input_amount, search, pruning)
+        };
+
+        if self.config.enabled {
+            self.insert(key, routes.clone());
+        }
+        routes
+    }
+
+    fn insert(&mut self, key: QuoteKey, routes: Vec<Rc<Route>>) {
+        if self.entries.len() >= self.config.max_entries {
+            self.clear();
+        }
+
+        //The ends are included so an entry with no routes still notices a pair showing up.
+        let mut tokens: HashSet<String> = routes.iter().flat_map(|x| x.path.iter().cloned()).collect();
+        tokens.insert(key.token_in.clone());
+        tokens.insert(key.token_out.clone());
+
+        for token in &tokens {
+            self.by_token.entry(token.clone()).or_insert(HashSet::new()).insert(key.clone());
+        }
+        self.entries.insert(key, QuoteEntry { routes: routes, tokens: tokens });
+    }
+
+    fn remove(&mut self, key: &QuoteKey) {
+        let entry = match self.entries.remove(key) {
+            Some(entry) => entry,
+            None => return
+        };
+        for token in &entry.tokens {
+            if let Some(keys) = self.by_token.get_mut(token) {
+                keys.remove(key);
+                if keys.len() == 0 {
+                    self.by_token.remove(token);
+                }
+            }
+        }
+        self.metrics.quote_cache_invalidations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    //Called with the pairs update_pairs changed. Every pair on a cached route touches one of its tokens, so checking tokens covers both.
+    pub fn invalidate_pairs(&mut self, pairs: &Vec<Rc<RefCell<Box<dyn Pair>>>>) {
+        let mut stale: HashSet<QuoteKey> = HashSet::new();
+        for pair_ref in pairs {
+            for token in (**pair_ref).borrow().get_token_arr() {
+                if let Some(keys) = self.by_token.get(token) {
+                    stale.extend(keys.iter().cloned());
+                }
+            }
+        }
+        for key in &stale {
+            self.remove(key);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.metrics.quote_cache_invalidations.fetch_add(self.entries.len() as u64, Ordering::Relaxed);
+        self.entries.clear();
+        self.by_token.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell, sync::Arc};
+    use std::sync::atomic::Ordering;
+
+    use super::{QuoteCache, QuoteCacheConfig};
+    use crate::metrics::{self, RouterMetrics};
+    use crate::pairs::Pair;
+    use crate::pairs::pancake_pair::{PancakePair, PancakeMetadata, pancake_pair_id};
+    use crate::pruning::{PruningConfig, build_pairs_by_token};
+    use crate::router::SearchConfig;
+
+    fn pair(module_address: &str, token_x: &str, token_y: &str) -> Rc<RefCell<Box<dyn Pair>>> {
+        Rc::new(RefCell::new(Box::new(PancakePair {
+            network: String::from("mainnet"),
+            protocol: String::from("pancake"),
+            pair_key: pancake_pair_id(module_address, token_x, token_y).key(),
+            pool_addr: String::from(module_address),
+            token_arr: vec![token_x.to_string(), token_y.to_string()],
+            router_pair_addr: String::new(),
+            metadata: PancakeMetadata { reserves: Some(vec![1000000000, 1000000000]), last_updated_version: None }
+        })))
+    }
+
+    fn counts(metrics: &Arc<RouterMetrics>) -> (u64, u64, u64) {
+        (
+            metrics.quote_cache_hits.load(Ordering::Relaxed),
+            metrics.quote_cache_misses.load(Ordering::Relaxed),
+            metrics.quote_cache_invalidations.load(Ordering::Relaxed)
+        )
+    }
+
+    /*
+    A->B and C->D share no tokens. A-E isn't on the A->B route but touches A, F-G touches neither.
+    Each test fills the cache with both quotes then updates one pair and re-asks for both.
+     */
+    fn check_invalidation(metrics_name: &str, updated: usize, a_b_evicted: bool) {
+        let pairs = vec![
+            pair("0x1", "A", "B"),
+            pair("0x1", "C", "D"),
+            pair("0x1", "A", "E"),
+            pair("0x1", "F", "G"),
+        ];
+        let pruning = PruningConfig::default();
+        let search = SearchConfig { max_swaps: 3, beam_width: 2, allow_token_revisits: false };
+        let pairs_by_token = build_pairs_by_token(&pairs, &pruning);
+        let metrics = metrics::router(metrics_name);
+        let mut cache = QuoteCache::new(QuoteCacheConfig::default(), metrics.clone());
+
+        let (a, b, c, d) = (String::from("A"), String::from("B"), String::from("C"), String::from("D"));
+        let input_amount = 1000000;
+
+        assert_eq!(cache.find_routes(&pairs_by_token, &a, &b, input_amount, &search, &pruning).len(), 1);
+        assert_eq!(cache.find_routes(&pairs_by_token, &c, &d, input_amount, &search, &pruning).len(), 1);
+        assert_eq!(counts(&metrics), (0, 2, 0));
+
+        //A nearby amount lands in the same bucket.
+        let hit = cache.find_routes(&pairs_by_token, &a, &b, input_amount + 1, &search, &pruning);
+        assert_eq!(hit[0].path_amounts[0], input_amount + 1);
+        assert_eq!(counts(&metrics), (1, 2, 0));
+
+        cache.invalidate_pairs(&vec![pairs[updated].clone()]);
+        let a_b_key = cache.key(&a, &b, input_amount);
+        let c_d_key = cache.key(&c, &d, input_amount);
+        assert_eq!(cache.entries.contains_key(&a_b_key), !a_b_evicted);
+        assert!(cache.entries.contains_key(&c_d_key));
+        assert_eq!(cache.by_token.contains_key("A"), !a_b_evicted);
+
+        let invalidations = if a_b_evicted { 1 } else { 0 };
+        assert_eq!(counts(&metrics), (1, 2, invalidations));
+
+        cache.find_routes(&pairs_by_token, &a, &b, input_amount, &search, &pruning);
+        cache.find_routes(&pairs_by_token, &c, &d, input_amount, &search, &pruning);
+        if a_b_evicted {
+            assert_eq!(counts(&metrics), (2, 3, 1));
+        } else {
+            assert_eq!(counts(&metrics), (3, 2, 0));
+        }
+    }
+
+    #[test]
+    fn updating_a_pair_on_a_cached_route_evicts_only_that_entry() {
+        check_invalidation("quote_cache_test_on_route", 0, true);
+    }
+
+    #[test]
+    fn updating_a_pair_touching_a_cached_routes_tokens_evicts_it() {
+        check_invalidation("quote_cache_test_adjacent", 2, true);
+    }
+
+    #[test]
+    fn updating_an_unrelated_pair_keeps_every_entry() {
+        check_invalidation("quote_cache_test_unrelated", 3, false);
+    }
+}
@@ -1,11 +1,16 @@
-use std::{collections::HashMap, rc::Rc, cell::RefCell};
+use std::{collections::HashMap, fs, rc::Rc, cell::RefCell};
 
+use serde::Deserialize;
 use serde_json::{self, Value};
+use anyhow::{Result, anyhow};
 
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::{
-    pairs::{liquidswap_pair::{self, LiquidswapMetadata, LiquidswapPair}, pancake_pair::{PancakeMetadata, PancakePair}, Pair, PairMetadata, PairNames}, registrys::{liquidswap_registry::LiquidswapRegistry, pancake_registry::PancakeRegistry}, types::Network
+    pairs::{Pair, PairMetadata, pancake_pair::pancake_from_value_descriptor, liquidswap_pair::liquidswap_from_value_descriptor}, registrys::{liquidswap_registry::LiquidswapRegistry, pancake_registry::PancakeRegistry}, types::{Network, VersionedChange, VersionedEvent},
+    data_sources::AptosDataSource,
+    pruning::build_pairs_by_token,
+    utils::{LEGACY_DESCRIPTORS_PATH, descriptors_path, read_pair_descriptors, write_pair_descriptors}
 };
 
 use async_trait::async_trait;
@@ -16,23 +21,38 @@ pub mod liquidswap_registry;
 #[async_trait]
 pub trait Registry: Send + Sync {
     fn module_address(&self) -> &str;
-    fn protocol(&self) -> PairNames;
-    async fn get_pairs(&self, network: &Network) -> Vec<Box<dyn Pair>>;
-    async fn get_metadata(&self, network: &Network, metadata_map: &mut HashMap<PairNames, HashMap<String, Box<dyn PairMetadata>> >);
-    fn build_metadata_map_from_changes(&self, changes: Vec<Value>) -> HashMap<String, Box<dyn PairMetadata>>;
+    //Key of this registry's protocol in protocol_constructors, matches its pairs' get_protocol().
+    fn protocol(&self) -> &str;
+    //Returns pairs discovered after the given high-water mark along with the new high-water mark.
+    async fn get_pairs(&self, network: &Network, source: &dyn AptosDataSource, high_water_mark: u64) -> Result<(Vec<Box<dyn Pair>>, u64)>;
+    //version is a ledger version the resources are known to be at least as new as.
+    async fn get_metadata(&self, source: &dyn AptosDataSource, version: u64, metadata_map: &mut HashMap<String, HashMap<String, Box<dyn PairMetadata>> >) -> Result<()>;
+    fn build_metadata_map_from_changes(&self, changes: &Vec<VersionedChange>) -> HashMap<String, Box<dyn PairMetadata>>;
+    //Type prefixes of the events build_metadata_map_from_events understands.
+    fn event_type_prefixes(&self) -> Vec<String>;
+    //Type prefix of the event emitted once per swap, used to calibrate per hop gas.
+    fn swap_event_type(&self) -> String;
+    //Applies swap/liquidity event deltas on top of current_metadata (keyed like the metadata map) and returns the pairs they touched.
+    //Events at or before a pair's current last_updated_version are already in its reserves and are skipped.
+    fn build_metadata_map_from_events(&self, events: &Vec<VersionedEvent>, current_metadata: &HashMap<String, Box<dyn PairMetadata>>) -> HashMap<String, Box<dyn PairMetadata>>;
+    fn metadata_from_value(&self, value: Value) -> Result<Box<dyn PairMetadata>>;
 }
 
-pub fn build_metadata_map_from_changes(registrys: &Vec<Box<dyn Registry>>, changes: Vec<Value>) -> HashMap<PairNames, HashMap<String, Box<dyn PairMetadata>> > {
+pub fn build_metadata_map_from_changes(registrys: &Vec<Box<dyn Registry>>, mut changes: Vec<VersionedChange>) -> HashMap<String, HashMap<String, Box<dyn PairMetadata>> > {
+    //Registrys rely on this ordering so the latest write to each pair is the one that ends up in the map.
+    changes.sort_by_key(|x| (x.version, x.index));
+
     let start_ms = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_millis();
 
-    let mut metadata_map: HashMap<PairNames, HashMap<String, Box<dyn PairMetadata>> > = HashMap::new();
+    let mut metadata_map: HashMap<String, HashMap<String, Box<dyn PairMetadata>> > = HashMap::new();
 
     for registry in registrys {
-        let protocol_metadata_map = registry.build_metadata_map_from_changes(changes.clone());
-        metadata_map.insert(registry.protocol(), protocol_metadata_map);
+        let protocol_metadata_map = registry.build_metadata_map_from_changes(&changes);
+        //Several registrys can share a protocol, eg. forks deployed at different addresses.
+        metadata_map.entry(registry.protocol().to_string()).or_insert(HashMap::new()).extend(protocol_metadata_map);
     }
 
     let end_ms = SystemTime::now()
@@ -45,21 +65,105 @@ pub fn build_metadata_map_from_changes(registrys: &Vec<Box<dyn Registry>>, chang
     metadata_map
 }
 
-pub fn get_all_registerys_from_json(network: &Network) -> Vec<Box<dyn Registry>> {
-    let json = serde_json::from_str::<Vec<Value>>(include_str!("../registerys.json")).unwrap();
-    
-    let pancake_registry_val = json.iter().find(|x| x["protocol"] == "pancake" && x["network"] == network.name).unwrap().clone();
-    let pancake_registry = Box::new(serde_json::from_value::<PancakeRegistry>(pancake_registry_val).unwrap()) as Box<dyn Registry>;
+pub fn build_metadata_map_from_events(registrys: &Vec<Box<dyn Registry>>, pairs: &Vec<Rc<RefCell<Box<dyn Pair>>>>, mut events: Vec<VersionedEvent>) -> HashMap<String, HashMap<String, Box<dyn PairMetadata>> > {
+    events.sort_by_key(|x| (x.version, x.index));
+
+    let mut metadata_map: HashMap<String, HashMap<String, Box<dyn PairMetadata>> > = HashMap::new();
+
+    for registry in registrys {
+        //Events are deltas, so the registry needs to know where each pair currently is.
+        let mut current_metadata: HashMap<String, Box<dyn PairMetadata>> = HashMap::new();
+        for pair_rc_ref in pairs {
+            let pair = (**pair_rc_ref).borrow();
+            if pair.get_protocol() != registry.protocol() {
+                continue;
+            }
+            if let Some(pair_metadata) = pair.get_metadata() {
+                current_metadata.insert(pair.metadata_key(), pair_metadata);
+            }
+        }
+
+        let protocol_metadata_map = registry.build_metadata_map_from_events(&events, &current_metadata);
+        metadata_map.entry(registry.protocol().to_string()).or_insert(HashMap::new()).extend(protocol_metadata_map);
+    }
+
+    metadata_map
+}
+
+//Builds a registry from its registerys.json entry.
+pub type RegistryConstructor = fn(Value) -> Result<Box<dyn Registry>>;
+pub type PairConstructor = fn(Value) -> Result<Box<dyn Pair>>;
 
-    let liquidswap_registry_val = json.iter().find(|x| x["protocol"] == "liquidswap_constant_product" && x["network"] == network.name).unwrap().clone();
-    let liquidswap_registry = Box::new(serde_json::from_value::<LiquidswapRegistry>(liquidswap_registry_val).unwrap()) as Box<dyn Registry>;
+pub struct ProtocolConstructors {
+    //From a registerys.json entry.
+    pub registry: RegistryConstructor,
+    //From a descriptor written by Pair::get_descriptor.
+    pub pair: PairConstructor,
+}
+
+//Protocol -> Constructors. The protocol is what its pairs' get_protocol() returns and what metadata is keyed by,
+//so supporting a new protocol is a matter of adding its constructors here.
+pub fn protocol_constructors() -> HashMap<&'static str, ProtocolConstructors> {
+    let mut constructors: HashMap<&'static str, ProtocolConstructors> = HashMap::new();
+    constructors.insert("pancake", ProtocolConstructors {
+        registry: PancakeRegistry::from_value,
+        pair: |descriptor| Ok(Box::new(pancake_from_value_descriptor(descriptor)?))
+    });
+    constructors.insert("liquidswap_constant_product", ProtocolConstructors {
+        registry: LiquidswapRegistry::from_value,
+        pair: |descriptor| Ok(Box::new(liquidswap_from_value_descriptor(descriptor)?))
+    });
+    constructors
+}
+
+fn default_enabled() -> bool { true }
+
+//The fields every registerys.json entry has, the rest is up to the protocol's constructor.
+#[derive(Deserialize)]
+struct RegistryEntry {
+    network: String,
+    protocol: String,
+    #[serde(default = "default_enabled")]
+    enabled: bool
+}
+
+pub fn get_all_registerys_from_json(path: &str, network: &Network) -> Result<Vec<Box<dyn Registry>>> {
+    let data = fs::read_to_string(path).map_err(|error| anyhow!("Failed to read {}: {}", path, error))?;
+    let json = serde_json::from_str::<Vec<Value>>(&data)?;
+    let constructors = protocol_constructors();
+
+    let mut registrys: Vec<Box<dyn Registry>> = Vec::new();
+    for (i, registry_val) in json.into_iter().enumerate() {
+        let entry = serde_json::from_value::<RegistryEntry>(registry_val.clone())
+            .map_err(|error| anyhow!("Registry entry {} in {}: {}", i, path, error))?;
+
+        //Check every entry's protocol, not just this network's, so typos show up wherever they are.
+        let constructor = match constructors.get(entry.protocol.as_str()) {
+            Some(constructor) => constructor,
+            None => return Err(anyhow!("Registry entry {} in {}: unknown protocol {}", i, path, entry.protocol))
+        };
+
+        if entry.network != network.name {
+            continue;
+        }
+        if !entry.enabled {
+            println!("Skipping disabled {} registry (entry {})", entry.protocol, i);
+            continue;
+        }
+
+        let registry = (constructor.registry)(registry_val)
+            .map_err(|error| anyhow!("Registry entry {} in {}: {}", i, path, error))?;
+        registrys.push(registry);
+    }
 
-    let registrys = vec![pancake_registry, liquidswap_registry];
-    return registrys;
+    if registrys.len() == 0 {
+        return Err(anyhow!("No enabled registrys for network {} in {}", network.name, path));
+    }
+    return Ok(registrys);
 }
 
-// pub fn all_registrys<'a>(network: Network) -> HashMap<PairNames, RegistryTypes> {
-//     let mut registryMap: HashMap<PairNames, RegistryTypes> = HashMap::new();
+// pub fn all_registrys<'a>(network: Network) -> HashMap<String, RegistryTypes> {
+//     let mut registryMap: HashMap<String, RegistryTypes> = HashMap::new();
 
 //     registryMap.insert(PairNames::PancakePair, 
 //         RegistryTypes::PancakeRegistry(
@@ -97,89 +201,171 @@ pub fn get_all_registerys_from_json(network: &Network) -> Vec<Box<dyn Registry>>
 //     return pairs_by_token_map.clone();
 // }   
 
-pub async fn gen_all_pairs(network: &Network, registrys: &mut Vec<Box<dyn Registry>>) -> (Vec<Rc<RefCell<Box<dyn Pair>>>>, HashMap<String, Vec<Rc<RefCell<Box<dyn Pair>>>> >) {
-    let mut pairs: Vec<Rc<RefCell<Box<dyn Pair>>>> = Vec::new();
-    let mut pairs_by_token: HashMap<String, Vec<Rc<RefCell<Box<dyn Pair>>>> > = HashMap::new();
-    for registry in registrys {
-        let reg_pairs = (*registry).get_pairs(network).await;
+pub async fn gen_all_pairs(network: &Network, source: &dyn AptosDataSource, registrys: &mut Vec<Box<dyn Registry>>) -> (Vec<Rc<RefCell<Box<dyn Pair>>>>, HashMap<String, Vec<Rc<RefCell<Box<dyn Pair>>>> >) {
+    let path = descriptors_path(network);
+    let (mut high_water_marks, stored_pairs) = match std::path::Path::new(&path).exists() {
+        true => read_pair_descriptors(&path),
+        false => read_pair_descriptors(LEGACY_DESCRIPTORS_PATH).map(|(marks, pairs)| {
+            //The shared file's high-water marks are only ours if it holds pairs for this network.
+            match pairs.iter().any(|x| x.get_network() == network.name) {
+                true => (marks, pairs),
+                false => (HashMap::new(), pairs)
+            }
+        })
+    }.unwrap_or_else(|error| {
+        eprintln!("Failed to read pair descriptors, crawling from scratch: {}", error);
+        (HashMap::new(), Vec::new())
+    });
+
+    //Pair Key -> Pair, newer crawls replace the stored descriptor for the same key.
+    let mut pairs_by_key: HashMap<String, Box<dyn Pair>> = HashMap::new();
+    let mut pair_key_order: Vec<String> = Vec::new();
+    for pair in stored_pairs {
+        if pair.get_network() != network.name {
+            continue;
+        }
+        let pair_key = pair.get_pair_key().to_string();
+        if !pairs_by_key.contains_key(&pair_key) {
+            pair_key_order.push(pair_key.clone());
+        }
+        pairs_by_key.insert(pair_key, pair);
+    }
+    println!("Loaded {} stored pairs", pairs_by_key.len());
+
+    for registry in registrys.iter() {
+        let high_water_mark = *high_water_marks.get(registry.module_address()).unwrap_or(&0);
+        let (reg_pairs, new_high_water_mark) = match (*registry).get_pairs(network, source, high_water_mark).await {
+            Ok(result) => result,
+            Err(error) => {
+                //Keep the stored pairs and high-water mark, we'll pick up the rest next startup.
+                eprintln!("Failed to get pairs for registry {}: {}", registry.module_address(), error);
+                continue;
+            }
+        };
+        high_water_marks.insert(registry.module_address().to_string(), new_high_water_mark);
 
         for pair in reg_pairs {
-            let token_arr = pair.get_token_arr().clone();
-            let pair_rc_refcel: Rc<RefCell<Box<dyn Pair>>> = Rc::new(RefCell::new(pair));
-
-            for token in token_arr {
-                if !pairs_by_token.contains_key(&token) {
-                    pairs_by_token.insert(token.clone(), Vec::new());
-                }
-                let pair_vec = pairs_by_token.get_mut(&token).unwrap();
-                pair_vec.push(pair_rc_refcel.clone());
+            let pair_key = pair.get_pair_key().to_string();
+            if !pairs_by_key.contains_key(&pair_key) {
+                pair_key_order.push(pair_key.clone());
             }
-
-            pairs.push(pair_rc_refcel.clone());
+            pairs_by_key.insert(pair_key, pair);
         }
+    }
+
+    let mut pairs: Vec<Rc<RefCell<Box<dyn Pair>>>> = Vec::new();
+    for pair_key in pair_key_order {
+        let pair = pairs_by_key.remove(&pair_key).unwrap();
+        pairs.push(Rc::new(RefCell::new(pair)));
+    }
+
+    if let Err(error) = write_pair_descriptors(&path, &high_water_marks, &pairs) {
+        eprintln!("Failed to write pair descriptors: {}", error);
+    }
+
+    //Pairs without reserves yet are left out, the router thread rebuilds this once metadata is in.
+    let pairs_by_token = build_pairs_by_token(&pairs, &network.pruning);
 
-    } 
     return (pairs, pairs_by_token);
 }
 
-pub async fn set_all_metadata(network: &Network, registrys: &mut Vec<Box<dyn Registry>>, metadata_map: &mut HashMap<PairNames, HashMap<String, Box<dyn PairMetadata>> >) {
+pub async fn set_all_metadata(source: &dyn AptosDataSource, registrys: &mut Vec<Box<dyn Registry>>, metadata_map: &mut HashMap<String, HashMap<String, Box<dyn PairMetadata>> >) -> Result<()> {
+    //Every registry reads its resources at this version, so the metadata is stamped with exactly the state it holds.
+    let version = source.get_ledger_version().await?;
     for registry in registrys {
-       (*registry).get_metadata(network, metadata_map).await;
+       (*registry).get_metadata(source, version, metadata_map).await?;
     }
+    Ok(())
+}
+
+//Metadata older than what the pair already has is never applied, unknown versions always are.
+fn is_stale(current_version: Option<u64>, new_version: Option<u64>) -> bool {
+    match (current_version, new_version) {
+        (Some(current_version), Some(new_version)) => new_version < current_version,
+        _ => false
+    }
+}
+
+pub struct PairUpdate {
+    pub pair: Rc<RefCell<Box<dyn Pair>>>,
+    //Reserves before and after this update. Taken when it's applied since the pair can be updated again
+    //before the updates are read, eg. a resync and a page of changes in the same message.
+    pub old_reserves: Option<Vec<u64>>,
+    pub new_reserves: Option<Vec<u64>>,
+    pub version: Option<u64>,
 }
 
-pub fn update_pairs(pairs: &mut Vec<Rc<RefCell<Box<dyn Pair>>>>, metadata_map: &mut HashMap<PairNames, HashMap<String, Box<dyn PairMetadata>> >) {
+//Returns the pairs that were updated.
+pub fn update_pairs(pairs: &mut Vec<Rc<RefCell<Box<dyn Pair>>>>, metadata_map: &mut HashMap<String, HashMap<String, Box<dyn PairMetadata>> >) -> Vec<PairUpdate> {
+    let mut updated: Vec<PairUpdate> = Vec::new();
     for pair_rc_ref in pairs {
         let mut pair = (**pair_rc_ref).borrow_mut();
-        let protocol = pair.get_protocol();
-        match protocol {
-            "pancake" => {
-                let mut pancake_pair = &mut *pair.as_any_mut().downcast_mut::<PancakePair>().unwrap();
-                let pancake_metadata_map = &*metadata_map.get(&PairNames::PancakePair).unwrap();
-
-                let identifier = format!("<{}, {}>", pancake_pair.token_arr[0], pancake_pair.token_arr[1]);
-                
-                if pancake_metadata_map.contains_key(&identifier) {
-                    let pancake_metadata: &PancakeMetadata = &*(*(pancake_metadata_map.get(&identifier).unwrap())).as_any().downcast_ref::<PancakeMetadata>().unwrap();
-                    
-                    let temp_res = pancake_metadata.reserves.clone().unwrap();
-                    let res_x = temp_res.get(0).unwrap();
-                    let res_y = temp_res.get(1).unwrap();
-                    
-
-                    if(pancake_pair.metadata.reserves.is_some()){
-                        let old_temp_res = pancake_pair.metadata.reserves.clone().unwrap();
-                        let old_res_x = old_temp_res.get(0).unwrap();
-                        let old_res_y = old_temp_res.get(1).unwrap();
-                    }  
-                    
-                    pancake_pair.metadata = pancake_metadata.clone();
-                }
-            } 
-
-            "liquidswap_constant_product" => {
-                let mut pair = &mut *pair.as_any_mut().downcast_mut::<LiquidswapPair>().unwrap();
-
-                let liquid_metadata_map = &*metadata_map.get(&PairNames::LiquidswapPair).unwrap();
-
-                let curve = if pair.curve_type == liquidswap_pair::CurveType::Uncorrelated {"Uncorrelated"} else {"Stable"};
-
-                let identifier = format!("{},{},{}", pair.token_arr[0], pair.token_arr[1], curve);
-                
-                if liquid_metadata_map.contains_key(&identifier) {
-                    let metadata: &LiquidswapMetadata = &*(*(liquid_metadata_map.get(&identifier).unwrap())).as_any().downcast_ref::<LiquidswapMetadata>().unwrap();
-                    
-                    let temp_res = metadata.reserves.clone();
-                    let res_x = temp_res.get(0).unwrap();
-                    let res_y = temp_res.get(1).unwrap();
-                    
-                    pair.metadata = metadata.clone();
-                }
-            }
 
-            &_ => {
+        let protocol_metadata_map = match metadata_map.get(pair.get_protocol()) {
+            Some(protocol_metadata_map) => protocol_metadata_map,
+            None => continue
+        };
+        let metadata = match protocol_metadata_map.get(&pair.metadata_key()) {
+            Some(metadata) => metadata,
+            None => continue
+        };
+
+        if is_stale(pair.last_updated_version(), metadata.last_updated_version()) {
+            continue;
+        }
 
-            }  
+        let old_reserves = pair.reserves();
+        match pair.apply_metadata(&**metadata) {
+            Ok(_) => updated.push(PairUpdate {
+                pair: pair_rc_ref.clone(),
+                old_reserves: old_reserves,
+                new_reserves: pair.reserves(),
+                version: pair.last_updated_version()
+            }),
+            Err(error) => eprintln!("Failed to update pair metadata: {}", error)
         }
     }
-}
\ No newline at end of file
+    updated
+}
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, rc::Rc, cell::RefCell};
+
+    use super::update_pairs;
+    use crate::pairs::{Pair, PairMetadata};
+    use crate::pairs::pancake_pair::{PancakePair, PancakeMetadata, pancake_pair_id};
+    use crate::reserve_feed::ReserveUpdate;
+
+    fn metadata_map(pair_key: &str, reserves: Vec<u64>, version: u64) -> HashMap<String, HashMap<String, Box<dyn PairMetadata>>> {
+        let mut protocol_metadata_map: HashMap<String, Box<dyn PairMetadata>> = HashMap::new();
+        protocol_metadata_map.insert(pair_key.to_string(), Box::new(PancakeMetadata { reserves: Some(reserves), last_updated_version: Some(version) }));
+        HashMap::from([(String::from("pancake"), protocol_metadata_map)])
+    }
+
+    //A resync and a page of changes in one message update the same pair twice, each update keeps its own reserves.
+    #[test]
+    fn updates_keep_the_reserves_they_applied() {
+        let pair_key = pancake_pair_id("0x1", "A", "B").key();
+        let mut pairs: Vec<Rc<RefCell<Box<dyn Pair>>>> = vec![Rc::new(RefCell::new(Box::new(PancakePair {
+            network: String::from("mainnet"),
+            protocol: String::from("pancake"),
+            pair_key: pair_key.clone(),
+            pool_addr: String::from("0x1"),
+            token_arr: vec![String::from("A"), String::from("B")],
+            router_pair_addr: String::new(),
+            metadata: PancakeMetadata { reserves: Some(vec![100, 100]), last_updated_version: Some(1) }
+        })))];
+
+        let mut pair_updates = update_pairs(&mut pairs, &mut metadata_map(&pair_key, vec![110, 91], 5));
+        pair_updates.extend(update_pairs(&mut pairs, &mut metadata_map(&pair_key, vec![120, 84], 6)));
+
+        let reserve_updates: Vec<ReserveUpdate> = pair_updates.iter().filter_map(|x| ReserveUpdate::from_pair_update(x)).collect();
+        assert_eq!(reserve_updates.len(), 2);
+        assert_eq!(reserve_updates[0].old_reserves, Some(vec![100, 100]));
+        assert_eq!(reserve_updates[0].new_reserves, Some(vec![110, 91]));
+        assert_eq!(reserve_updates[0].version, Some(5));
+        assert_eq!(reserve_updates[1].old_reserves, Some(vec![110, 91]));
+        assert_eq!(reserve_updates[1].new_reserves, Some(vec![120, 84]));
+        assert_eq!(reserve_updates[1].version, Some(6));
+    }
+}
@@ -3,16 +3,18 @@ use aptos_sdk::types::account_config::token;
 use async_trait::async_trait;
 
 use crate::{
-    types::{Network}, 
+    types::{Network, VersionedChange, VersionedEvent}, 
     pairs::{
         Pair, 
-        liquidswap_pair::{LiquidswapPair, CurveType, LiquidswapMetadata},
-        PairNames, PairMetadata
+        liquidswap_pair::{LiquidswapPair, CurveType, LiquidswapMetadata, liquidswap_pair_id},
+        PairMetadata
     },
-    utils::{query_aptos_events_raw, string_to_u64, query_aptos_resources_all_raw}};
+    data_sources::AptosDataSource,
+    utils::{value_u64, pointer_u64, apply_reserve_delta}};
 use serde::{Serialize, Deserialize};
 use serde_json::{Value, json};
 use regex::Regex;
+use anyhow::Result;
 use super::{Registry};
 
 
@@ -52,133 +54,298 @@ pub struct LiquidswapRegistry {
     protocol: String
 }
 
+impl LiquidswapRegistry {
+    pub fn from_value(value: Value) -> Result<Box<dyn Registry>> {
+        Ok(Box::new(serde_json::from_value::<LiquidswapRegistry>(value)?))
+    }
+
+    //None for resources that aren't a LiquidityPool.
+    fn pair_from_resource(&self, network_name: &str, re: &Regex, resource: &Value) -> Result<Option<LiquidswapPair>> {
+        let (token_x, token_y, curve_type) = match pool_type(re, resource) {
+            Some(pool_type) => pool_type,
+            None => return Ok(None)
+        };
+        let reserves = pool_reserves(resource)?;
+        let pair_key = liquidswap_pair_id(&self.module_address, &token_x, &token_y, &curve_type).key();
+
+        Ok(Some(LiquidswapPair {
+            network: String::from(network_name),
+            protocol: String::from("liquidswap_constant_product"),
+            pair_key: pair_key,
+            pool_addr: self.module_address.clone(),
+            token_arr: vec![token_x, token_y],
+            curve_type: curve_type,
+            x_scale: pointer_u64(resource, "/data/x_scale")?,
+            y_scale: pointer_u64(resource, "/data/y_scale")?,
+            router_pair_addr: String::new(),
+            fee: pointer_u64(resource, "/data/fee")?,
+            dao_fee: pointer_u64(resource, "/data/dao_fee")?,
+            metadata: LiquidswapMetadata { reserves: reserves, last_updated_version: None }
+        }))
+    }
+}
+
+//Token x, token y and curve of a LiquidityPool resource.
+fn pool_type(re: &Regex, resource: &Value) -> Option<(String, String, CurveType)> {
+    let type_str = resource.get("type").and_then(|x| x.as_str())?;
+    let captures = re.captures(type_str)?;
+    Some((captures.get(2)?.as_str().to_string(), captures.get(3)?.as_str().to_string(), CurveType::from_type_str(captures.get(4)?.as_str())))
+}
+
+fn pool_reserves(resource: &Value) -> Result<Vec<u64>> {
+    Ok(vec![pointer_u64(resource, "/data/coin_x_reserve/value")?, pointer_u64(resource, "/data/coin_y_reserve/value")?])
+}
+
 #[async_trait]
 impl Registry for LiquidswapRegistry {
     fn module_address(&self) -> &str {
         return &self.module_address;
     }
 
-    fn protocol(&self) -> PairNames {
-        return PairNames::LiquidswapPair;
+    fn protocol(&self) -> &str {
+        return &self.protocol;
     }
 
-    async fn get_pairs(&self, network: &Network) -> Vec<Box<dyn Pair>>{
+    //Liquidswap pools are read from the account resources rather than events, so there is no 
+    //high-water mark and every pool is returned (with its current reserves).
+    async fn get_pairs(&self, network: &Network, source: &dyn AptosDataSource, high_water_mark: u64) -> Result<(Vec<Box<dyn Pair>>, u64)>{
         println!("Getting Liquidswap Pairs...");
-        let network_http = &network.http[..];
         let network_name = &network.name[..];
     
-        let account = "0x61d2c22a6cb7831bee0f48363b0eec92369357aece0d1142062f7d5d85c7bef8";
+        //Pools are resources of the resource account, not the account the module is published at.
+        let account = &self.module_address[..];
         
-        let all_resources_raw = query_aptos_resources_all_raw(network_http, account).await;
-        let all_resources:Vec<Value> = serde_json::from_str(&all_resources_raw).unwrap();
-        let mut liquidity_pool_resources: Vec<Value> = vec![];
-
-        for res in all_resources {
-            let type_str = res.get("type").unwrap().clone();
-            if(type_str.as_str().unwrap().contains(&"::liquidity_pool::LiquidityPool".to_string())){
-                liquidity_pool_resources.push(res.clone());
+        let all_resources:Vec<Value> = source.get_account_resources(account).await?;
+        let re = Regex::new(r"(.*)::liquidity_pool::LiquidityPool<([^,]+),\s*([^,]+),\s*([^>]+)>").unwrap();
+    
+        let mut liquidswap_pairs: Vec<Box<dyn Pair>> = vec![];
+        for resource in all_resources {
+            match self.pair_from_resource(network_name, &re, &resource) {
+                Ok(Some(liquidswap_pair)) => liquidswap_pairs.push(Box::new(liquidswap_pair)),
+                Ok(None) => {}
+                Err(error) => println!("Skipping Liquidswap pool resource: {}", error)
             }
         }
+        println!("Liq Pool Length: {}", liquidswap_pairs.len());
+        return Ok((liquidswap_pairs, high_water_mark));
+    }
+
+    async fn get_metadata(&self, source: &dyn AptosDataSource, version: u64, metadata_map: &mut HashMap<String, HashMap<String, Box<dyn PairMetadata>> >) -> Result<()>{
+        println!("Getting Liquidswap Metadata...");
+        let account = &self.module_address[..];
 
-        println!("Liq Pool Length: {}", liquidity_pool_resources.len());
+        let all_resources: Vec<Value> = source.get_account_resources_at(account, version).await?;
         let re = Regex::new(r"(.*)::liquidity_pool::LiquidityPool<([^,]+),\s*([^,]+),\s*([^>]+)>").unwrap();
-    
-        let mut liquidswap_pairs: Vec<Box<dyn Pair>> = vec![];
-        for pair_data in liquidity_pool_resources {
-            let type_str = pair_data.get("type").unwrap().clone();
-            let captures = re.captures(type_str.as_str().unwrap()).expect("Captures Failed");
-            let token_x = captures.get(2).unwrap().as_str().to_string();
-            let token_y = captures.get(3).unwrap().as_str().to_string();
-            let curve_str = captures.get(4).unwrap().as_str().to_string();
-            let curve_type = if curve_str.contains("Uncorrelated") { CurveType::Uncorrelated } else { CurveType::Stable };
-            let token_x_reserve_str = pair_data.get("data").unwrap().get("coin_x_reserve").unwrap().get("value").unwrap().as_str().unwrap().to_string();
-            let token_y_reserve_str = pair_data.get("data").unwrap().get("coin_y_reserve").unwrap().get("value").unwrap().as_str().unwrap().to_string();
-            let token_x_reserve = token_x_reserve_str.parse::<u64>().unwrap();
-            let token_y_reserve = token_y_reserve_str.parse::<u64>().unwrap();
-            let x_scale_str = pair_data.get("data").unwrap().get("x_scale").unwrap().as_str().unwrap().to_string();
-            let y_scale_str = pair_data.get("data").unwrap().get("y_scale").unwrap().as_str().unwrap().to_string();
-            let x_scale = x_scale_str.parse::<u64>().unwrap();
-            let y_scale = y_scale_str.parse::<u64>().unwrap();
-            let fee_str = pair_data.get("data").unwrap().get("fee").unwrap().as_str().unwrap().to_string();
-            let fee = fee_str.parse::<u64>().unwrap();
-            let dao_fee_str = pair_data.get("data").unwrap().get("dao_fee").unwrap().as_str().unwrap().to_string();
-            let dao_fee = dao_fee_str.parse::<u64>().unwrap();
-
-            let pair_key = format!("{}{}{}", account, token_x, token_y);
-
-            let liquidswap_pair = LiquidswapPair {
-                network: String::from(network_name),
-                protocol: String::from("liquidswap_constant_product"),
-                pair_name: PairNames::PancakePair,
-                pair_key: String::from(pair_key),
-                pool_addr: String::from(account),
-                token_arr: Vec::from([token_x.clone(),token_y]),
-                curve_type: curve_type,
-                x_scale: x_scale,
-                y_scale: y_scale,
-                router_pair_addr: String::new(),
-                fee: fee,
-                dao_fee: dao_fee,
-                metadata: LiquidswapMetadata { reserves: vec![token_x_reserve, token_y_reserve] }
+
+        let mut liquidswap_map: HashMap<String, Box<dyn PairMetadata>> = HashMap::new();
+        for resource in all_resources {
+            let (token_x, token_y, curve_type) = match pool_type(&re, &resource) {
+                Some(pool_type) => pool_type,
+                None => continue
+            };
+            let reserves = match pool_reserves(&resource) {
+                Ok(reserves) => reserves,
+                Err(error) => {
+                    println!("Skipping Liquidswap pool resource {}<{}, {}>: {}", account, token_x, token_y, error);
+                    continue;
+                }
             };
-            
-            liquidswap_pairs.push(Box::new(liquidswap_pair));
-            
+
+            let identifier = liquidswap_pair_id(account, &token_x, &token_y, &curve_type).key();
+            liquidswap_map.insert(identifier, Box::new(LiquidswapMetadata { reserves: reserves, last_updated_version: Some(version) }));
         }
-        return liquidswap_pairs;
-    }
 
-    async fn get_metadata(&self, network: &Network, metadata_map: &mut HashMap<PairNames, HashMap<String, Box<dyn PairMetadata>> >){
-        println!("Inserting Liquidswap Hashmap.");
-        metadata_map.insert(PairNames::LiquidswapPair, HashMap::new());
+        metadata_map.entry(self.protocol.clone()).or_insert(HashMap::new()).extend(liquidswap_map);
+        Ok(())
     }
 
-    fn build_metadata_map_from_changes(&self, changes: Vec<Value>) -> HashMap<String, Box<dyn PairMetadata>> {
+    fn build_metadata_map_from_changes(&self, changes: &Vec<VersionedChange>) -> HashMap<String, Box<dyn PairMetadata>> {
 
         println!("Building Liquidswap Metadata From Changes...");
 
         let mut metadata_map: HashMap<String, Box<dyn PairMetadata>> = HashMap::new();
+        let type_re = Regex::new(r#".*?::liquidity_pool::LiquidityPool<([^"]+), ([^"]+),.*?::curves::([^"]+)>.*?"#).unwrap();
 
-        // \\\"{\\\"address\\\":\\\"([^"]+)\\\"(.*)\{\\\"type\\\":\\\"([^"]+)\\\"(.*)\\\"reserve_x\\\":\\\"([^"]+)\\\"(.*)\\\"reserve_y\\\":\\\"([^"]+)\\\"(.*)/gm
-
-        for change in changes {
-            let re = Regex::new(r#""address":"([^"]+)".*?"type":"([^"]+)".*?"coin_x_reserve":.*?"value":"([^"]+)".*?"coin_y_reserve":.*?"value":"([^"]+)""#).unwrap();
-            let type_re = Regex::new(r#".*?::liquidity_pool::LiquidityPool<([^"]+), ([^"]+),.*?::curves::([^"]+)>.*?"#).unwrap();
-            if let Some(captures) = re.captures(change.to_string().as_str()){
-                if let (Some(address), Some(type_str), Some(reserve_x), Some(reserve_y)) = (
-                    captures.get(1).map(|m| m.as_str()),
-                    captures.get(2).map(|m| m.as_str()),
-                    captures.get(3).map(|m| m.as_str()),
-                    captures.get(4).map(|m| m.as_str())
-                ) {
-                    // println!("Captured First");
-                    if let Some(type_captures) = type_re.captures(type_str) {
-                        if let (Some(token_x), Some(token_y), Some(curve)) = (
-                            type_captures.get(1).map(|m| m.as_str()),
-                            type_captures.get(2).map(|m| m.as_str()),
-                            type_captures.get(3).map(|m| m.as_str())
-                        ){
-                            let identifier = format!("{},{},{}", token_x, token_y, curve);
-                            
-                            // println!("Identifier: {}", identifier);
-
-                            let res_x = reserve_x.parse::<u64>().unwrap();
-                            let res_y = reserve_y.parse::<u64>().unwrap();
-    
-                            let metadata = LiquidswapMetadata {
-                                reserves: vec![res_x, res_y]
-                            };
-                
-                            metadata_map.insert(identifier.clone(), Box::new(metadata));
-                        }
-                    }
+        //Changes are in (version, index) order so a later write to the same pool replaces an earlier one.
+        for versioned_change in changes {
+            let change = &versioned_change.change;
+            if change.get("address").and_then(|x| x.as_str()) != Some(&self.module_address[..]) {
+                continue;
+            }
+
+            let type_str = match change.pointer("/data/type").and_then(|x| x.as_str()) {
+                Some(type_str) => type_str,
+                None => continue
+            };
+
+            let reserve_x = change.pointer("/data/data/coin_x_reserve/value").and_then(|x| x.as_str()).and_then(|x| x.parse::<u64>().ok());
+            let reserve_y = change.pointer("/data/data/coin_y_reserve/value").and_then(|x| x.as_str()).and_then(|x| x.parse::<u64>().ok());
+
+            if let (Some(type_captures), Some(res_x), Some(res_y)) = (type_re.captures(type_str), reserve_x, reserve_y) {
+                if let (Some(token_x), Some(token_y), Some(curve)) = (
+                    type_captures.get(1).map(|m| m.as_str()),
+                    type_captures.get(2).map(|m| m.as_str()),
+                    type_captures.get(3).map(|m| m.as_str())
+                ){
+                    let identifier = liquidswap_pair_id(&self.module_address, token_x, token_y, &CurveType::from_type_str(curve)).key();
+
+                    let metadata = LiquidswapMetadata {
+                        reserves: vec![res_x, res_y],
+                        last_updated_version: Some(versioned_change.version)
+                    };
+
+                    metadata_map.insert(identifier, Box::new(metadata));
                 }
             }
         }
 
         return metadata_map;
-        // return HashMap::new()
+    }
+
+    fn event_type_prefixes(&self) -> Vec<String> {
+        return vec![format!("{}::liquidity_pool::", self.pool_address)];
+    }
+
+    fn swap_event_type(&self) -> String {
+        return format!("{}::liquidity_pool::SwapEvent<", self.pool_address);
+    }
+
+    fn build_metadata_map_from_events(&self, events: &Vec<VersionedEvent>, current_metadata: &HashMap<String, Box<dyn PairMetadata>>) -> HashMap<String, Box<dyn PairMetadata>> {
+
+        println!("Building Liquidswap Metadata From Events...");
+
+        let type_re = Regex::new(r#"^(.*?)::liquidity_pool::(SwapEvent|LiquidityAddedEvent|LiquidityRemovedEvent)<([^,]+), ([^,]+),.*?::curves::([^>]+)>$"#).unwrap();
+
+        //Pair Key -> Metadata with every event so far applied.
+        let mut working_map: HashMap<String, LiquidswapMetadata> = HashMap::new();
+
+        for versioned_event in events {
+            let event = &versioned_event.event;
+            let type_str = event.get("type").and_then(|x| x.as_str()).unwrap_or("");
+            let data = match event.get("data") {
+                Some(data) => data,
+                None => continue
+            };
+
+            let captures = match type_re.captures(type_str) {
+                Some(captures) if captures.get(1).unwrap().as_str() == self.pool_address => captures,
+                _ => continue
+            };
+            let event_name = captures.get(2).unwrap().as_str();
+            let identifier = liquidswap_pair_id(
+                &self.module_address,
+                captures.get(3).unwrap().as_str(),
+                captures.get(4).unwrap().as_str(),
+                &CurveType::from_type_str(captures.get(5).unwrap().as_str())
+            ).key();
+
+            let mut metadata = match working_map.get(&identifier) {
+                Some(metadata) => metadata.clone(),
+                None => match current_metadata.get(&identifier).and_then(|x| x.as_any().downcast_ref::<LiquidswapMetadata>()) {
+                    //Already in reserves read at or after this event.
+                    Some(metadata) if metadata.last_updated_version.map_or(false, |x| versioned_event.version <= x) => continue,
+                    Some(metadata) => metadata.clone(),
+                    //A delta on a pool we've never read reserves for can't be applied, wait for the next reconcile.
+                    None => continue
+                }
+            };
+
+            let reserves = &mut metadata.reserves;
+            match event_name {
+                "SwapEvent" => {
+                    //The dao fee leaves the pool, that drift gets corrected on reconcile.
+                    reserves[0] = apply_reserve_delta(reserves[0], value_u64(data, "x_in").unwrap_or(0), value_u64(data, "x_out").unwrap_or(0));
+                    reserves[1] = apply_reserve_delta(reserves[1], value_u64(data, "y_in").unwrap_or(0), value_u64(data, "y_out").unwrap_or(0));
+                }
+                "LiquidityAddedEvent" => {
+                    reserves[0] = apply_reserve_delta(reserves[0], value_u64(data, "added_x_val").unwrap_or(0), 0);
+                    reserves[1] = apply_reserve_delta(reserves[1], value_u64(data, "added_y_val").unwrap_or(0), 0);
+                }
+                _ => {
+                    reserves[0] = apply_reserve_delta(reserves[0], 0, value_u64(data, "returned_x_val").unwrap_or(0));
+                    reserves[1] = apply_reserve_delta(reserves[1], 0, value_u64(data, "returned_y_val").unwrap_or(0));
+                }
+            }
+
+            metadata.last_updated_version = Some(versioned_event.version);
+            working_map.insert(identifier, metadata);
+        }
+
+        return working_map.into_iter().map(|(k, v)| (k, Box::new(v) as Box<dyn PairMetadata>)).collect();
+    }
+
+    fn metadata_from_value(&self, value: Value) -> Result<Box<dyn PairMetadata>> {
+        let metadata = serde_json::from_value::<LiquidswapMetadata>(value)?;
+        return Ok(Box::new(metadata));
     }
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::LiquidswapRegistry;
+    use crate::pairs::{PairMetadata, liquidswap_pair::{LiquidswapMetadata, CurveType, liquidswap_pair_id}};
+    use crate::registrys::Registry;
+    use crate::types::VersionedEvent;
+
+    const POOL: &str = "0x163d";
+    const MODULE: &str = "0x61d2";
+
+    fn registry() -> LiquidswapRegistry {
+        LiquidswapRegistry { pool_address: POOL.to_string(), module_address: MODULE.to_string(), protocol: String::from("liquidswap") }
+    }
+
+    fn pair_key() -> String {
+        liquidswap_pair_id(MODULE, "0x1::a::A", "0x1::b::B", &CurveType::Uncorrelated).key()
+    }
+
+    fn event(version: u64, name: &str, data: serde_json::Value) -> VersionedEvent {
+        let type_str = format!("{}::liquidity_pool::{}<0x1::a::A, 0x1::b::B, {}::curves::Uncorrelated>", POOL, name, POOL);
+        VersionedEvent { version: version, index: 0, event: json!({"type": type_str, "data": data}) }
+    }
+
+    fn current(reserves: Vec<u64>, version: u64) -> HashMap<String, Box<dyn PairMetadata>> {
+        let mut current: HashMap<String, Box<dyn PairMetadata>> = HashMap::new();
+        current.insert(pair_key(), Box::new(LiquidswapMetadata { reserves: reserves, last_updated_version: Some(version) }));
+        current
+    }
+
+    fn applied(metadata_map: &HashMap<String, Box<dyn PairMetadata>>) -> (Vec<u64>, Option<u64>) {
+        let metadata = metadata_map[&pair_key()].as_any().downcast_ref::<LiquidswapMetadata>().unwrap();
+        (metadata.reserves.clone(), metadata.last_updated_version)
+    }
+
+    #[test]
+    fn applies_swap_add_and_remove_deltas() {
+        let events = vec![
+            event(6, "SwapEvent", json!({"x_in": "100", "x_out": "0", "y_in": "0", "y_out": "150"})),
+            event(7, "LiquidityAddedEvent", json!({"added_x_val": "10", "added_y_val": "20"})),
+            event(8, "LiquidityRemovedEvent", json!({"returned_x_val": "110", "returned_y_val": "70"})),
+        ];
+        let metadata_map = registry().build_metadata_map_from_events(&events, &current(vec![1000, 2000], 5));
+        assert_eq!(applied(&metadata_map), (vec![1000, 1800], Some(8)));
+    }
+
+    #[test]
+    fn skips_events_already_in_reconciled_reserves() {
+        //Reserves were read at 10 by a reconcile, the page runs from 9 to 11.
+        let events = vec![
+            event(9, "SwapEvent", json!({"x_in": "100", "x_out": "0", "y_in": "0", "y_out": "150"})),
+            event(10, "LiquidityAddedEvent", json!({"added_x_val": "10", "added_y_val": "20"})),
+            event(11, "SwapEvent", json!({"x_in": "0", "x_out": "50", "y_in": "100", "y_out": "0"})),
+        ];
+        let metadata_map = registry().build_metadata_map_from_events(&events, &current(vec![1000, 2000], 10));
+        assert_eq!(applied(&metadata_map), (vec![950, 2100], Some(11)));
+    }
+
+    #[test]
+    fn ignores_pools_without_reserves() {
+        let events = vec![event(6, "SwapEvent", json!({"x_in": "100", "x_out": "0", "y_in": "0", "y_out": "150"}))];
+        assert!(registry().build_metadata_map_from_events(&events, &HashMap::new()).is_empty());
+    }
+}
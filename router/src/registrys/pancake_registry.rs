@@ -1,10 +1,10 @@
 use std::{collections::HashMap};
 use async_trait::async_trait;
 
-use crate::{types::{Network}, pairs::{Pair, pancake_pair::{PancakePair, PancakeMetadata}, PairNames, PairMetadata}, utils::{query_aptos_events_raw, string_to_u64, query_aptos_resources_all_raw}};
+use crate::{types::{Network, VersionedChange, VersionedEvent}, pairs::{Pair, pancake_pair::{PancakePair, PancakeMetadata, pancake_pair_id}, PairMetadata}, utils::{string_to_u64, value_u64, pointer_u64, apply_reserve_delta, type_args}, data_sources::AptosDataSource};
 use serde::{Serialize, Deserialize};
 use serde_json::Value;
-use regex::Regex;
+use anyhow::Result;
 use super::{Registry};
 
 
@@ -54,30 +54,44 @@ pub struct PancakeRegistry {
     protocol: String
 }
 
+impl PancakeRegistry {
+    pub fn from_value(value: Value) -> Result<Box<dyn Registry>> {
+        Ok(Box::new(serde_json::from_value::<PancakeRegistry>(value)?))
+    }
+
+    //"<X, Y>" from a TokenPairReserve or event type -> the pair's key in the metadata map.
+    fn pair_key_from_type_args(&self, type_args_str: &str) -> Option<String> {
+        match &type_args(type_args_str)[..] {
+            [token_x, token_y] => Some(pancake_pair_id(&self.module_address, token_x, token_y).key()),
+            _ => None
+        }
+    }
+}
+
 #[async_trait]
 impl Registry for PancakeRegistry {
     fn module_address(&self) -> &str {
         return &self.module_address;
     }
 
-    fn protocol(&self) -> PairNames {
-        return PairNames::PancakePair;
+    fn protocol(&self) -> &str {
+        return &self.protocol;
     }
 
-    async fn get_pairs(&self, network: &Network) -> Vec<Box<dyn Pair>>{
-        let network_http = &network.http[..];
+    async fn get_pairs(&self, network: &Network, source: &dyn AptosDataSource, high_water_mark: u64) -> Result<(Vec<Box<dyn Pair>>, u64)>{
         let network_name = &network.name[..];
     
-        let account = "0xc7efb4076dbe143cbcd98cfaaa929ecfc8f299203dfff63b95ccb6bfe19850fa";
-        let event = "0xc7efb4076dbe143cbcd98cfaaa929ecfc8f299203dfff63b95ccb6bfe19850fa::swap::SwapInfo/pair_created";
+        let account = &self.module_address[..];
+        let event = &format!("{}::swap::SwapInfo/pair_created", account)[..];
         
         let mut all_pancake_pairs:Vec<Box<dyn Pair>> = Vec::new();
     
         let mut query: bool = true;
-        let mut start: u64 = 0;
+        let mut start: u64 = high_water_mark;
         while query {
-            let raw_data = query_aptos_events_raw(network_http, account, event, start, 100).await;
-            let data: Vec<PancakeData> = serde_json::from_str(&raw_data).unwrap();
+            let raw_data = source.get_events(account, event, start, 100).await?;
+            let data: Vec<PancakeData> = serde_json::from_value(Value::Array(raw_data))?;
+            let page_len = data.len() as u64;
     
             if data.len() < 100 {
                 query = false;
@@ -86,53 +100,59 @@ impl Registry for PancakeRegistry {
     
             for pair_data in data {
                 // println!("X: {} Y: {}", pair_data.data.token_x, pair_data.data.token_y);
-                let pair_key = format!("{}{}{}", account, pair_data.data.token_x, pair_data.data.token_y);
+                let pair_key = pancake_pair_id(account, &pair_data.data.token_x, &pair_data.data.token_y).key();
     
                 let pancake_pair = PancakePair {
                     network: String::from(network_name),
                     protocol: String::from("pancake"),
-                    pair_name: PairNames::PancakePair,
                     pair_key: String::from(pair_key),
                     pool_addr: String::from(account),
                     token_arr: Vec::from([pair_data.data.token_x, pair_data.data.token_y]),
                     router_pair_addr: String::new(),
                     metadata: PancakeMetadata {
                         reserves: None,
+                        last_updated_version: None
                     }
                 };
     
                 all_pancake_pairs.push(Box::new(pancake_pair));
             }
     
-            start += 100;
+            start += page_len;
         };
 
-        return all_pancake_pairs;
+        println!("Found {} new pancake pairs after event {}", all_pancake_pairs.len(), high_water_mark);
+        return Ok((all_pancake_pairs, start));
     }
 
-    async fn get_metadata(&self, network: &Network, metadata_map: &mut HashMap<PairNames, HashMap<String, Box<dyn PairMetadata>> >){
-        let network_http = &network.http[..];
-    
-        let account = "0xc7efb4076dbe143cbcd98cfaaa929ecfc8f299203dfff63b95ccb6bfe19850fa";
+    async fn get_metadata(&self, source: &dyn AptosDataSource, version: u64, metadata_map: &mut HashMap<String, HashMap<String, Box<dyn PairMetadata>> >) -> Result<()>{
+        let account = &self.module_address[..];
     
-        let all_resources_raw = query_aptos_resources_all_raw(network_http, account).await;
-    
-        let all_resources:Vec<serde_json::Value> = serde_json::from_str(&all_resources_raw).unwrap();
+        let all_resources:Vec<serde_json::Value> = source.get_account_resources_at(account, version).await?;
 
         let mut pancake_map: HashMap<String, Box<dyn PairMetadata>> = HashMap::new();
         
+        let reserve_type = format!("{}::swap::TokenPairReserve", account);
         let mut count = 0;
         for resource in all_resources {
-            let _type = resource.get("type").unwrap().as_str().unwrap();
-            if _type.contains(&format!("{}::swap::TokenPairReserve", account)) {
-                let token_names = String::from(&_type[90..]);
+            let _type = resource.get("type").and_then(|x| x.as_str()).unwrap_or("");
+            if _type.starts_with(&reserve_type) {
+                let token_names = match self.pair_key_from_type_args(&_type[reserve_type.len()..]) {
+                    Some(token_names) => token_names,
+                    None => continue
+                };
     
-                let data = resource.get("data").unwrap();
-                let res_x = data.get("reserve_x").unwrap().as_str().unwrap().parse::<u64>().unwrap();
-                let res_y = data.get("reserve_y").unwrap().as_str().unwrap().parse::<u64>().unwrap();
+                let reserves = match (pointer_u64(&resource, "/data/reserve_x"), pointer_u64(&resource, "/data/reserve_y")) {
+                    (Ok(res_x), Ok(res_y)) => vec![res_x, res_y],
+                    (Err(error), _) | (_, Err(error)) => {
+                        println!("Skipping {}: {}", _type, error);
+                        continue;
+                    }
+                };
     
                 let metadata = PancakeMetadata {
-                    reserves: Some(vec![res_x, res_y])
+                    reserves: Some(reserves),
+                    last_updated_version: Some(version)
                 };
     
                 pancake_map.insert(token_names.clone(), Box::new(metadata));
@@ -140,44 +160,204 @@ impl Registry for PancakeRegistry {
                 count += 1;
             }
         }
-        metadata_map.insert(PairNames::PancakePair, pancake_map);
+        metadata_map.entry(self.protocol.clone()).or_insert(HashMap::new()).extend(pancake_map);
+        Ok(())
     }
 
-    fn build_metadata_map_from_changes(&self, changes: Vec<Value>) -> HashMap<String, Box<dyn PairMetadata>> {
+    fn build_metadata_map_from_changes(&self, changes: &Vec<VersionedChange>) -> HashMap<String, Box<dyn PairMetadata>> {
 
         println!("Building Pancake Metadata From Changes...");
 
+        let reserve_type = format!("{}::swap::TokenPairReserve", self.module_address);
         let mut metadata_map: HashMap<String, Box<dyn PairMetadata>> = HashMap::new();
 
-        // \\\"{\\\"address\\\":\\\"([^"]+)\\\"(.*)\{\\\"type\\\":\\\"([^"]+)\\\"(.*)\\\"reserve_x\\\":\\\"([^"]+)\\\"(.*)\\\"reserve_y\\\":\\\"([^"]+)\\\"(.*)/gm
-
-        for change in changes {
-            let re = Regex::new(r#""address":"([^"]+)".*?"type":"([^"]+)".*?"reserve_x":"([^"]+)".*?"reserve_y":"([^"]+)""#).unwrap();
-            
-            if let Some(captures) = re.captures(change.to_string().as_str()){
-                if let (Some(address), Some(type_str), Some(reserve_x), Some(reserve_y)) = (
-                    captures.get(1).map(|m| m.as_str()),
-                    captures.get(2).map(|m| m.as_str()),
-                    captures.get(3).map(|m| m.as_str()),
-                    captures.get(4).map(|m| m.as_str())
-                ) {
-                    let token_names = String::from(&type_str[90..]);
-                    println!("Token Names: {}", token_names);
-                    let res_x = reserve_x.parse::<u64>().unwrap();
-                    let res_y = reserve_y.parse::<u64>().unwrap();
-
-                    let metadata = PancakeMetadata {
-                        reserves: Some(vec![res_x, res_y])
-                    };
-        
-                    metadata_map.insert(token_names.clone(), Box::new(metadata));
+        //Changes are in (version, index) order so a later write to the same pair replaces an earlier one.
+        for versioned_change in changes {
+            let change = &versioned_change.change;
+            if change.get("address").and_then(|x| x.as_str()) != Some(&self.module_address[..]) {
+                continue;
+            }
+
+            let type_str = match change.pointer("/data/type").and_then(|x| x.as_str()) {
+                Some(type_str) if type_str.starts_with(&reserve_type) => type_str,
+                _ => continue
+            };
+
+            let reserve_x = change.pointer("/data/data/reserve_x").and_then(|x| x.as_str()).and_then(|x| x.parse::<u64>().ok());
+            let reserve_y = change.pointer("/data/data/reserve_y").and_then(|x| x.as_str()).and_then(|x| x.parse::<u64>().ok());
+            if let (Some(res_x), Some(res_y)) = (reserve_x, reserve_y) {
+                let token_names = match self.pair_key_from_type_args(&type_str[reserve_type.len()..]) {
+                    Some(token_names) => token_names,
+                    None => continue
+                };
+                println!("Token Names: {} at version {}", token_names, versioned_change.version);
+
+                let metadata = PancakeMetadata {
+                    reserves: Some(vec![res_x, res_y]),
+                    last_updated_version: Some(versioned_change.version)
+                };
+
+                metadata_map.insert(token_names, Box::new(metadata));
+            }
+        }
+
+        return metadata_map;
+    }
+
+    fn event_type_prefixes(&self) -> Vec<String> {
+        return vec![format!("{}::swap::", self.module_address)];
+    }
+
+    fn swap_event_type(&self) -> String {
+        return format!("{}::swap::SwapEvent<", self.module_address);
+    }
+
+    fn build_metadata_map_from_events(&self, events: &Vec<VersionedEvent>, current_metadata: &HashMap<String, Box<dyn PairMetadata>>) -> HashMap<String, Box<dyn PairMetadata>> {
+
+        println!("Building Pancake Metadata From Events...");
+
+        let swap_type = format!("{}::swap::SwapEvent", self.module_address);
+        let add_type = format!("{}::swap::AddLiquidityEvent", self.module_address);
+        let remove_type = format!("{}::swap::RemoveLiquidityEvent", self.module_address);
+        let sync_type = format!("{}::swap::SyncEvent", self.module_address);
+
+        //Pair Key -> Metadata with every event so far applied.
+        let mut working_map: HashMap<String, PancakeMetadata> = HashMap::new();
+
+        for versioned_event in events {
+            let event = &versioned_event.event;
+            let type_str = event.get("type").and_then(|x| x.as_str()).unwrap_or("");
+            let data = match event.get("data") {
+                Some(data) => data,
+                None => continue
+            };
+
+            let event_type = [&swap_type, &add_type, &remove_type, &sync_type].into_iter()
+                .find(|x| type_str.starts_with(&x[..]) && type_str[x.len()..].starts_with('<'));
+            let event_type = match event_type {
+                Some(event_type) => event_type,
+                None => continue
+            };
+            let token_names = match self.pair_key_from_type_args(&type_str[event_type.len()..]) {
+                Some(token_names) => token_names,
+                None => continue
+            };
+
+            let mut metadata = match working_map.get(&token_names) {
+                Some(metadata) => metadata.clone(),
+                None => match current_metadata.get(&token_names).and_then(|x| x.as_any().downcast_ref::<PancakeMetadata>()) {
+                    //Reserves read at or after this event, eg. by a reconcile ahead of the watcher, already include it.
+                    Some(metadata) if metadata.last_updated_version.map_or(false, |x| versioned_event.version <= x) => continue,
+                    Some(metadata) => metadata.clone(),
+                    None => PancakeMetadata { reserves: None, last_updated_version: None }
+                }
+            };
+
+            if event_type == &sync_type {
+                if let (Some(res_x), Some(res_y)) = (value_u64(data, "reserve_x"), value_u64(data, "reserve_y")) {
+                    metadata.reserves = Some(vec![res_x, res_y]);
+                }
+            } else if let Some(reserves) = metadata.reserves.as_mut() {
+                if event_type == &swap_type {
+                    reserves[0] = apply_reserve_delta(reserves[0], value_u64(data, "amount_x_in").unwrap_or(0), value_u64(data, "amount_x_out").unwrap_or(0));
+                    reserves[1] = apply_reserve_delta(reserves[1], value_u64(data, "amount_y_in").unwrap_or(0), value_u64(data, "amount_y_out").unwrap_or(0));
+                } else if event_type == &add_type {
+                    reserves[0] = apply_reserve_delta(reserves[0], value_u64(data, "amount_x").unwrap_or(0), 0);
+                    reserves[1] = apply_reserve_delta(reserves[1], value_u64(data, "amount_y").unwrap_or(0), 0);
+                } else {
+                    reserves[0] = apply_reserve_delta(reserves[0], 0, value_u64(data, "amount_x").unwrap_or(0));
+                    reserves[1] = apply_reserve_delta(reserves[1], 0, value_u64(data, "amount_y").unwrap_or(0));
                 }
             }
+
+            //A delta on a pair we've never read reserves for can't be applied, wait for the next reconcile.
+            if metadata.reserves.is_none() {
+                continue;
+            }
+            metadata.last_updated_version = Some(versioned_event.version);
+            working_map.insert(token_names, metadata);
         }
 
+        return working_map.into_iter().map(|(k, v)| (k, Box::new(v) as Box<dyn PairMetadata>)).collect();
+    }
 
-        return metadata_map;
+    fn metadata_from_value(&self, value: Value) -> Result<Box<dyn PairMetadata>> {
+        let metadata = serde_json::from_value::<PancakeMetadata>(value)?;
+        return Ok(Box::new(metadata));
     }
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::PancakeRegistry;
+    use crate::pairs::{PairMetadata, pancake_pair::{PancakeMetadata, pancake_pair_id}};
+    use crate::registrys::Registry;
+    use crate::types::VersionedEvent;
+
+    const MODULE: &str = "0xabc";
+
+    fn registry() -> PancakeRegistry {
+        PancakeRegistry { module_address: MODULE.to_string(), protocol: String::from("pancake") }
+    }
+
+    fn pair_key() -> String {
+        pancake_pair_id(MODULE, "0x1::a::A", "0x1::b::B").key()
+    }
+
+    fn event(version: u64, name: &str, data: serde_json::Value) -> VersionedEvent {
+        VersionedEvent { version: version, index: 0, event: json!({"type": format!("{}::swap::{}<0x1::a::A, 0x1::b::B>", MODULE, name), "data": data}) }
+    }
+
+    fn current(reserves: Vec<u64>, version: u64) -> HashMap<String, Box<dyn PairMetadata>> {
+        let mut current: HashMap<String, Box<dyn PairMetadata>> = HashMap::new();
+        current.insert(pair_key(), Box::new(PancakeMetadata { reserves: Some(reserves), last_updated_version: Some(version) }));
+        current
+    }
+
+    fn applied(metadata_map: &HashMap<String, Box<dyn PairMetadata>>) -> (Vec<u64>, Option<u64>) {
+        let metadata = metadata_map[&pair_key()].as_any().downcast_ref::<PancakeMetadata>().unwrap();
+        (metadata.reserves.clone().unwrap(), metadata.last_updated_version)
+    }
+
+    #[test]
+    fn applies_swap_add_and_remove_deltas() {
+        let events = vec![
+            event(6, "SwapEvent", json!({"amount_x_in": "100", "amount_x_out": "0", "amount_y_in": "0", "amount_y_out": "150"})),
+            event(7, "AddLiquidityEvent", json!({"amount_x": "10", "amount_y": "20"})),
+            event(8, "RemoveLiquidityEvent", json!({"amount_x": "110", "amount_y": "70"})),
+        ];
+        let metadata_map = registry().build_metadata_map_from_events(&events, &current(vec![1000, 2000], 5));
+        assert_eq!(applied(&metadata_map), (vec![1000, 1800], Some(8)));
+    }
+
+    #[test]
+    fn sync_replaces_reserves() {
+        let events = vec![
+            event(6, "SwapEvent", json!({"amount_x_in": "100", "amount_x_out": "0", "amount_y_in": "0", "amount_y_out": "150"})),
+            event(6, "SyncEvent", json!({"reserve_x": "1099", "reserve_y": "1851"})),
+        ];
+        let metadata_map = registry().build_metadata_map_from_events(&events, &current(vec![1000, 2000], 5));
+        assert_eq!(applied(&metadata_map), (vec![1099, 1851], Some(6)));
+    }
+
+    #[test]
+    fn skips_events_already_in_reconciled_reserves() {
+        //Reserves were read at 10 by a reconcile, the page runs from 9 to 11.
+        let events = vec![
+            event(9, "SwapEvent", json!({"amount_x_in": "100", "amount_x_out": "0", "amount_y_in": "0", "amount_y_out": "150"})),
+            event(10, "AddLiquidityEvent", json!({"amount_x": "10", "amount_y": "20"})),
+            event(11, "SwapEvent", json!({"amount_x_in": "0", "amount_x_out": "50", "amount_y_in": "100", "amount_y_out": "0"})),
+        ];
+        let metadata_map = registry().build_metadata_map_from_events(&events, &current(vec![1000, 2000], 10));
+        assert_eq!(applied(&metadata_map), (vec![950, 2100], Some(11)));
+
+        let metadata_map = registry().build_metadata_map_from_events(&events[..2].to_vec(), &current(vec![1000, 2000], 10));
+        assert!(metadata_map.is_empty());
+    }
+}
@@ -0,0 +1,66 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Deserialize};
+
+use crate::registrys::PairUpdate;
+
+//A reserve change applied by update_pairs, what the reserve feed sends.
+#[derive(Clone, Serialize)]
+pub struct ReserveUpdate {
+    pub pair_id: String,
+    pub protocol: String,
+    pub tokens: Vec<String>,
+    pub old_reserves: Option<Vec<u64>>,
+    pub new_reserves: Option<Vec<u64>>,
+    //Ledger version of the change.
+    pub version: Option<u64>,
+    //When the router applied it, milliseconds since the epoch.
+    pub timestamp_ms: u64,
+}
+
+impl ReserveUpdate {
+    //None when the update didn't move reserves, eg. a resync finding them unchanged.
+    pub fn from_pair_update(pair_update: &PairUpdate) -> Option<ReserveUpdate> {
+        if pair_update.new_reserves == pair_update.old_reserves {
+            return None;
+        }
+        let pair = (*pair_update.pair).borrow();
+        Some(ReserveUpdate {
+            pair_id: pair.get_pair_key().to_string(),
+            protocol: pair.get_protocol().to_string(),
+            tokens: pair.get_token_arr().clone(),
+            old_reserves: pair_update.old_reserves.clone(),
+            new_reserves: pair_update.new_reserves.clone(),
+            version: pair_update.version,
+            timestamp_ms: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |x| x.as_millis() as u64),
+        })
+    }
+}
+
+//Query string of the reserve feed, anything left out isn't filtered on.
+#[derive(Deserialize, Clone, Default)]
+pub struct ReserveFeedFilter {
+    #[serde(default)]
+    pub network: Option<String>,
+    #[serde(default)]
+    pub protocol: Option<String>,
+    //Pairs trading this token.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+impl ReserveFeedFilter {
+    pub fn matches(&self, protocol: &str, tokens: &Vec<String>) -> bool {
+        if let Some(filter_protocol) = &self.protocol {
+            if filter_protocol != protocol {
+                return false;
+            }
+        }
+        if let Some(filter_token) = &self.token {
+            if !tokens.contains(filter_token) {
+                return false;
+            }
+        }
+        true
+    }
+}
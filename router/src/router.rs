@@ -1,6 +1,7 @@
 use std::{collections::HashMap, rc::Rc, cell::RefCell};
 
 use crate::pairs::Pair;
+use crate::pruning::PruningConfig;
 
 pub struct Route {
     pub pairs: Vec<Rc<RefCell<Box<dyn Pair>>>>,
@@ -8,104 +9,147 @@ pub struct Route {
     pub path_amounts: Vec<u64>
 }
 
+impl Route {
+    pub fn output_amount(&self) -> u64 {
+        self.path_amounts[self.path_amounts.len()-1]
+    }
+}
+
+//How routes are searched, from RouterConfig.
+#[derive(Clone)]
+pub struct SearchConfig {
+    pub max_swaps: u8,
+    pub beam_width: usize,
+    //Let a route pass through the same token more than once (through different pairs), eg. A->B->A->C.
+    pub allow_token_revisits: bool
+}
+
 pub fn find_best_routes_for_fixed_input_amount(
     pairs_by_token: &HashMap<String, Vec<Rc<RefCell<Box<dyn Pair>>>>>,
     input_token: &String,
     output_token: &String,
     input_amount: u64,
-    max_swaps: u8
+    search: &SearchConfig,
+    pruning: &PruningConfig
 ) -> Vec<Rc<Route>>{
+    //A route back to the input token is a cycle, those are asked for through find_cyclic_routes.
+    if input_token == output_token {
+        return Vec::new();
+    }
+    search_routes(pairs_by_token, input_token, output_token, input_amount, search, pruning)
+}
 
-    let mut completed_routes: Vec<Rc<Route>> = Vec::new();
-
-    //Token Address -> Route
-    let mut current_routes: HashMap<String, Rc<Route>> = HashMap::new();
+//Routes that start and end at token (A->...->A), best first. Anything ending with more than input_amount is an arbitrage.
+pub fn find_cyclic_routes(
+    pairs_by_token: &HashMap<String, Vec<Rc<RefCell<Box<dyn Pair>>>>>,
+    token: &String,
+    input_amount: u64,
+    search: &SearchConfig,
+    pruning: &PruningConfig
+) -> Vec<Rc<Route>>{
+    search_routes(pairs_by_token, token, token, input_amount, search, pruning)
+}
 
-    //Token Address -> OutputAmount
-    let mut max_output_amounts: HashMap<String, u64> = HashMap::new();  
+/*
+Beam search over routes. Every depth keeps the beam_width best routes into each token instead of just one,
+since output is non-linear in input: a route that reaches an intermediate token with less can still come out
+ahead after the next hops (different pools, different price impact). A token's beam is also kept per depth,
+so a longer route isn't dropped because a shorter one reached the same token with more.
+Routes stop once they reach output_token, the input token is only ever revisited by a cycle's last hop.
+Returns every route that reaches output_token, best first.
+ */
+fn search_routes(
+    pairs_by_token: &HashMap<String, Vec<Rc<RefCell<Box<dyn Pair>>>>>,
+    input_token: &String,
+    output_token: &String,
+    input_amount: u64,
+    search: &SearchConfig,
+    pruning: &PruningConfig
+) -> Vec<Rc<Route>>{
 
-    current_routes.insert(input_token.to_string(), Rc::new(Route {
-         pairs: Vec::new(), 
-         path: vec![input_token.to_string()], 
-         path_amounts: vec![input_amount] 
-        }
-    ));
+    let mut completed_routes: Vec<Rc<Route>> = Vec::new();
 
-    max_output_amounts.insert(input_token.to_string(), input_amount);
+    let mut current_routes: Vec<Rc<Route>> = vec![Rc::new(Route {
+        pairs: Vec::new(),
+        path: vec![input_token.to_string()],
+        path_amounts: vec![input_amount]
+    })];
 
     let mut d=0;
-    while d<max_swaps {
+    while d<search.max_swaps {
 
-        //Token Addr -> Route
-        let mut next_routes: HashMap<String, Rc<Route>> = HashMap::new();
-        for route in current_routes.values_mut() {
+        //Token Addr -> Routes ending in it this depth
+        let mut next_routes: HashMap<String, Vec<Rc<Route>>> = HashMap::new();
+        for route in &current_routes {
             let route_output_token = &route.path[route.path.len()-1];
-            let route_output_amount = route.path_amounts[route.path_amounts.len()-1];
-            let matching_pairs = pairs_by_token.get(route_output_token).unwrap();
+            let route_output_amount = route.output_amount();
+            //The token can be missing from the graph when all of its pairs were pruned.
+            let matching_pairs = match pairs_by_token.get(route_output_token) {
+                Some(matching_pairs) => matching_pairs,
+                None => continue
+            };
             for pair_ref in matching_pairs {
                 let pair = (**pair_ref).borrow();
                 let token_arr = pair.get_token_arr();
 
-                for pair_output_token in token_arr {
+                //A pool's reserves only move once per transaction, so using it twice would be priced wrong.
+                if route.pairs.iter().any(|p_ref| (**p_ref).borrow().get_pair_key() == pair.get_pair_key()) {
+                    continue;
+                }
+
+                //Pool too shallow for the amount coming into this hop.
+                if !pruning.hop_allowed(&**pair, route_output_token, route_output_amount) {
+                    continue;
+                }
 
+                for pair_output_token in token_arr {
                     if pair_output_token == route_output_token {
                         continue;
                     }
 
-                    if !token_arr.contains(&route_output_token) {
-                        panic!("route output token not contained in token_arr");
-                    }
-
-                    //skip if pair already exists in the route, we have already explored better routes before.
-                    let mut pair_key_already_exists = false;
-                    for p_ref in &route.pairs {
-                        let p = (**p_ref).borrow();
-                        if p.get_pair_key() == pair.get_pair_key() {
-                            pair_key_already_exists = true;
+                    //The input token can only be revisited to close a cycle, other tokens only when the policy allows it.
+                    let revisit = route.path.contains(pair_output_token);
+                    if revisit && pair_output_token == input_token {
+                        if pair_output_token != output_token {
+                            continue;
                         }
-                    }
-                    if pair.get_pair_key() != String::new() && pair_key_already_exists {
+                    } else if revisit && !search.allow_token_revisits {
                         continue;
                     }
-                    
-                    let pair_output_amount = pair.output_amount(route_output_amount, route_output_token, pair_output_token);
-                    let cur_max_ouput = max_output_amounts.get(pair_output_token).unwrap_or(&0);
 
-                    //check to see if we have a better route
-                    if cur_max_ouput >= &pair_output_amount {
+                    let pair_output_amount = pair.output_amount(route_output_amount, route_output_token, pair_output_token);
+                    if pair_output_amount == 0 {
                         continue;
                     }
 
-                    max_output_amounts.insert(pair_output_token.clone(), pair_output_amount);
-                    
-                    let mut new_pairs: Vec<Rc<RefCell<Box<dyn Pair>>>> = Vec::new();
-                    new_pairs.extend(route.pairs.iter().cloned());
+                    let mut new_pairs: Vec<Rc<RefCell<Box<dyn Pair>>>> = route.pairs.clone();
                     new_pairs.push(pair_ref.clone());
 
-                    let mut new_path: Vec<String> = Vec::new();
-                    new_path.extend(route.path.iter().cloned());
+                    let mut new_path: Vec<String> = route.path.clone();
                     new_path.push(pair_output_token.to_string());
 
-                    let mut new_path_amounts: Vec<u64> = Vec::new();
-                    new_path_amounts.extend(route.path_amounts.iter().cloned());
+                    let mut new_path_amounts: Vec<u64> = route.path_amounts.clone();
                     new_path_amounts.push(pair_output_amount);
 
-                    let pair_route = Route {
+                    next_routes.entry(pair_output_token.to_string()).or_insert(Vec::new()).push(Rc::new(Route {
                         pairs: new_pairs,
                         path: new_path,
                         path_amounts: new_path_amounts
-                    };
-
-                    let pair_route_ref = Rc::new(pair_route);
-
-                    next_routes.insert(pair_output_token.to_string(), pair_route_ref.clone());
-                    if pair_output_token == output_token {
-                        completed_routes.push(pair_route_ref);
-                    }
+                    }));
                 }
             }
         }
-        current_routes = next_routes;
+
+        current_routes = Vec::new();
+        for (token, mut routes) in next_routes {
+            routes.sort_by(|a, b| b.output_amount().cmp(&a.output_amount()));
+            routes.truncate(search.beam_width);
+            if &token == output_token {
+                completed_routes.extend(routes);
+            } else {
+                current_routes.extend(routes);
+            }
+        }
 
         if current_routes.len() == 0 {
             break;
@@ -114,7 +158,75 @@ pub fn find_best_routes_for_fixed_input_amount(
         d+=1;
     }
 
-    completed_routes.sort_by(|a, b| a.path_amounts[a.path_amounts.len()-1].cmp(&b.path_amounts[b.path_amounts.len()-1]));
+    //Fewer hops wins a tie.
+    completed_routes.sort_by(|a, b| b.output_amount().cmp(&a.output_amount()).then(a.pairs.len().cmp(&b.pairs.len())));
 
     return completed_routes;
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, rc::Rc, cell::RefCell};
+
+    use super::{Route, SearchConfig, find_best_routes_for_fixed_input_amount};
+    use crate::pairs::Pair;
+    use crate::pairs::pancake_pair::{PancakePair, PancakeMetadata, pancake_pair_id};
+    use crate::pruning::{PruningConfig, build_pairs_by_token};
+
+    fn pair(module_address: &str, token_x: &str, token_y: &str, reserve_x: u64, reserve_y: u64) -> Rc<RefCell<Box<dyn Pair>>> {
+        Rc::new(RefCell::new(Box::new(PancakePair {
+            network: String::from("mainnet"),
+            protocol: String::from("pancake"),
+            pair_key: pancake_pair_id(module_address, token_x, token_y).key(),
+            pool_addr: String::from(module_address),
+            token_arr: vec![token_x.to_string(), token_y.to_string()],
+            router_pair_addr: String::new(),
+            metadata: PancakeMetadata { reserves: Some(vec![reserve_x, reserve_y]), last_updated_version: None }
+        })))
+    }
+
+    fn search(beam_width: usize) -> SearchConfig {
+        SearchConfig { max_swaps: 4, beam_width: beam_width, allow_token_revisits: false }
+    }
+
+    fn path(tokens: &[&str]) -> Vec<String> {
+        tokens.iter().map(|x| x.to_string()).collect()
+    }
+
+    /*
+    A->C->B (through cb_1) reaches B with the most, but C is then used up, and C is the only way on to D.
+    A->E->B reaches B with less and goes on B->C (through cb_2, which prices C higher) ->D, ending with about 3x
+    what A->C->D gets. Keeping only the best route into each token drops A->E->B at depth 2.
+     */
+    fn graph() -> (Vec<Rc<RefCell<Box<dyn Pair>>>>, HashMap<String, Vec<Rc<RefCell<Box<dyn Pair>>>>>) {
+        let pairs = vec![
+            pair("0x1", "A", "C", 1000000000, 1000000000),
+            pair("0x1", "C", "B", 1000000000, 2000000000),
+            pair("0x2", "C", "B", 2000000000, 1000000000),
+            pair("0x1", "A", "E", 1000000000, 1000000000),
+            pair("0x1", "E", "B", 1000000000, 1500000000),
+            pair("0x1", "C", "D", 1000000000, 1000000000),
+        ];
+        let pairs_by_token = build_pairs_by_token(&pairs, &PruningConfig::default());
+        (pairs, pairs_by_token)
+    }
+
+    #[test]
+    fn beam_search_keeps_a_lower_intermediate_route_that_ends_higher() {
+        let (pairs, pairs_by_token) = graph();
+        let input_amount = 1000000;
+
+        //The most B can be had for at depth 2 is through C.
+        let via_c = Route { pairs: vec![pairs[0].clone(), pairs[1].clone()], path: path(&["A", "C", "B"]), path_amounts: Vec::new() };
+        let via_e = Route { pairs: vec![pairs[3].clone(), pairs[4].clone()], path: path(&["A", "E", "B"]), path_amounts: Vec::new() };
+        assert!(via_e.simulate_output(input_amount) < via_c.simulate_output(input_amount));
+
+        //One route per token is what the old search kept.
+        let single = find_best_routes_for_fixed_input_amount(&pairs_by_token, &String::from("A"), &String::from("D"), input_amount, &search(1), &PruningConfig::default());
+        assert_eq!(single[0].path, path(&["A", "C", "D"]));
+
+        let beam = find_best_routes_for_fixed_input_amount(&pairs_by_token, &String::from("A"), &String::from("D"), input_amount, &search(2), &PruningConfig::default());
+        assert_eq!(beam[0].path, path(&["A", "E", "B", "C", "D"]));
+        assert!(beam[0].output_amount() > single[0].output_amount());
+    }
+}
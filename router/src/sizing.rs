@@ -0,0 +1,231 @@
+use std::{rc::Rc, cell::RefCell};
+
+use serde::Deserialize;
+
+use crate::pairs::Pair;
+use crate::router::Route;
+
+//Marginal prices are measured with the hop's input reserve divided by this.
+const PROBE_DIVISOR: u64 = 10000;
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SizingTarget {
+    //Cycles only, the input that leaves the most left over.
+    MaxProfit,
+    //The largest input whose price impact stays within max_price_impact (0.01 = 1%).
+    PriceImpact { max_price_impact: f64 },
+    //The largest input that still gets at least min_price output per input, both in smallest units.
+    MinPrice { min_price: f64 },
+}
+
+/*
+Sizing a fixed route. Output is increasing and concave in input for every curve we support, so
+execution price and price impact are monotone in size (binary search) and a cycle's profit has a
+single peak (golden-section search). Amounts are searched between 1 and the first hop's input reserve.
+ */
+impl Route {
+    pub fn simulate(&self, input_amount: u64) -> Vec<u64> {
+        let mut path_amounts = vec![input_amount];
+        for (i, pair_ref) in self.pairs.iter().enumerate() {
+            let pair = (**pair_ref).borrow();
+            let amount = pair.output_amount(path_amounts[i], &self.path[i], &self.path[i+1]);
+            path_amounts.push(amount);
+        }
+        path_amounts
+    }
+
+    pub fn simulate_output(&self, input_amount: u64) -> u64 {
+        let path_amounts = self.simulate(input_amount);
+        path_amounts[path_amounts.len()-1]
+    }
+
+    pub fn is_cycle(&self) -> bool {
+        self.pairs.len() > 0 && self.path[0] == self.path[self.path.len()-1]
+    }
+
+    //Output per input at the margin, fees included.
+    pub fn marginal_price(&self) -> Option<f64> {
+        let mut price = 1.0;
+        for (i, pair_ref) in self.pairs.iter().enumerate() {
+            let pair = (**pair_ref).borrow();
+            let reserves = pair.reserves()?;
+            let in_index = pair.get_token_arr().iter().position(|x| *x == self.path[i])?;
+            let probe_amount = std::cmp::max(1, reserves[in_index] / PROBE_DIVISOR);
+            let output_amount = pair.output_amount(probe_amount, &self.path[i], &self.path[i+1]);
+            if output_amount == 0 {
+                return None;
+            }
+            price *= output_amount as f64 / probe_amount as f64;
+        }
+        Some(price)
+    }
+
+    pub fn execution_price(&self, input_amount: u64) -> f64 {
+        self.simulate_output(input_amount) as f64 / input_amount as f64
+    }
+
+    //How much worse than the marginal price input_amount executes at, 0.0 to 1.0.
+    pub fn price_impact(&self, input_amount: u64) -> Option<f64> {
+        let marginal_price = self.marginal_price()?;
+        Some((1.0 - self.execution_price(input_amount) / marginal_price).max(0.0))
+    }
+
+    fn max_input_amount(&self) -> Option<u64> {
+        let pair = (*self.pairs[0]).borrow();
+        let in_index = pair.get_token_arr().iter().position(|x| *x == self.path[0])?;
+        let reserves = pair.reserves()?;
+        Some(std::cmp::max(1, reserves[in_index]))
+    }
+
+    fn profit(&self, input_amount: u64) -> i128 {
+        self.simulate_output(input_amount) as i128 - input_amount as i128
+    }
+
+    //None if the cycle isn't profitable at any size.
+    pub fn max_profit_input(&self) -> Option<u64> {
+        if !self.is_cycle() {
+            return None;
+        }
+        let mut lo = 1.0;
+        let mut hi = self.max_input_amount()? as f64;
+        let inv_phi = (5f64.sqrt() - 1.0) / 2.0;
+
+        let mut m1 = hi - (hi - lo) * inv_phi;
+        let mut m2 = lo + (hi - lo) * inv_phi;
+        let mut p1 = self.profit(m1 as u64);
+        let mut p2 = self.profit(m2 as u64);
+        while hi - lo > 2.0 {
+            if p1 < p2 {
+                lo = m1;
+                m1 = m2;
+                p1 = p2;
+                m2 = lo + (hi - lo) * inv_phi;
+                p2 = self.profit(m2 as u64);
+            } else {
+                hi = m2;
+                m2 = m1;
+                p2 = p1;
+                m1 = hi - (hi - lo) * inv_phi;
+                p1 = self.profit(m1 as u64);
+            }
+        }
+
+        //Integer amounts, check what's left of the bracket.
+        let best = (lo.floor() as u64..=hi.ceil() as u64).max_by_key(|x| self.profit(*x))?;
+        if self.profit(best) > 0 { Some(best) } else { None }
+    }
+
+    //Largest input where ok(input) holds, for a condition that holds up to some size and not past it.
+    fn largest_input_where(&self, ok: impl Fn(u64) -> bool) -> Option<u64> {
+        let mut lo = 1;
+        let mut hi = self.max_input_amount()?;
+        if !ok(lo) {
+            return None;
+        }
+        while lo < hi {
+            let mid = lo + (hi - lo + 1) / 2;
+            if ok(mid) {
+                lo = mid;
+            } else {
+                hi = mid - 1;
+            }
+        }
+        Some(lo)
+    }
+
+    pub fn input_for_price_impact(&self, max_price_impact: f64) -> Option<u64> {
+        let marginal_price = self.marginal_price()?;
+        self.largest_input_where(|x| 1.0 - self.execution_price(x) / marginal_price <= max_price_impact)
+    }
+
+    pub fn input_for_min_price(&self, min_price: f64) -> Option<u64> {
+        self.largest_input_where(|x| self.execution_price(x) >= min_price)
+    }
+
+    pub fn input_for_target(&self, target: &SizingTarget) -> Option<u64> {
+        match target {
+            SizingTarget::MaxProfit => self.max_profit_input(),
+            SizingTarget::PriceImpact { max_price_impact } => self.input_for_price_impact(*max_price_impact),
+            SizingTarget::MinPrice { min_price } => self.input_for_min_price(*min_price),
+        }
+    }
+}
+
+//Rebuilds a route from its token_in and pair ids, eg. from a previous route response.
+pub fn route_from_pair_ids(pairs: &Vec<Rc<RefCell<Box<dyn Pair>>>>, token_in: &String, pair_ids: &Vec<String>) -> Result<Route, String> {
+    if pair_ids.len() == 0 {
+        return Err(String::from("pair_ids is empty"));
+    }
+
+    let mut route = Route { pairs: Vec::new(), path: vec![token_in.clone()], path_amounts: Vec::new() };
+    for pair_id in pair_ids {
+        let pair_ref = match pairs.iter().find(|x| (***x).borrow().get_pair_key() == pair_id) {
+            Some(pair_ref) => pair_ref,
+            None => return Err(format!("Unknown pair {}", pair_id))
+        };
+        //Same rule as the search, a pool's reserves only move once per transaction.
+        if route.pairs.iter().any(|x| Rc::ptr_eq(x, pair_ref)) {
+            return Err(format!("Pair {} is used more than once", pair_id));
+        }
+        let token = route.path[route.path.len()-1].clone();
+        let next_token = {
+            let pair = (**pair_ref).borrow();
+            //These pairs aren't pruned, and simulating through one without reserves or with an empty side panics.
+            if !pair.reserves().map_or(false, |reserves| reserves.iter().all(|x| *x > 0)) {
+                return Err(format!("Pair {} has no liquidity", pair_id));
+            }
+            let token_arr = pair.get_token_arr();
+            if !token_arr.contains(&token) {
+                return Err(format!("Pair {} doesn't trade {}", pair_id, token));
+            }
+            match token_arr.iter().find(|x| **x != token) {
+                Some(next_token) => next_token.clone(),
+                None => return Err(format!("Pair {} has no other token", pair_id))
+            }
+        };
+        route.pairs.push(pair_ref.clone());
+        route.path.push(next_token);
+    }
+    Ok(route)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, cell::RefCell};
+
+    use super::route_from_pair_ids;
+    use crate::pairs::Pair;
+    use crate::pairs::pancake_pair::{PancakePair, PancakeMetadata, pancake_pair_id};
+
+    fn pair(token_x: &str, token_y: &str, reserves: Option<Vec<u64>>) -> Rc<RefCell<Box<dyn Pair>>> {
+        Rc::new(RefCell::new(Box::new(PancakePair {
+            network: String::from("mainnet"),
+            protocol: String::from("pancake"),
+            pair_key: pancake_pair_id("0x1", token_x, token_y).key(),
+            pool_addr: String::from("0x1"),
+            token_arr: vec![token_x.to_string(), token_y.to_string()],
+            router_pair_addr: String::new(),
+            metadata: PancakeMetadata { reserves: reserves, last_updated_version: None }
+        })))
+    }
+
+    fn key(token_x: &str, token_y: &str) -> String {
+        pancake_pair_id("0x1", token_x, token_y).key()
+    }
+
+    #[test]
+    fn rejects_hops_without_liquidity_and_reused_pairs() {
+        let pairs = vec![
+            pair("A", "B", Some(vec![1000, 1000])),
+            pair("B", "C", None),
+            pair("C", "D", Some(vec![1000, 0])),
+        ];
+        let token_in = String::from("A");
+
+        assert!(route_from_pair_ids(&pairs, &token_in, &vec![key("A", "B")]).is_ok());
+        assert!(route_from_pair_ids(&pairs, &token_in, &vec![key("A", "B"), key("B", "C")]).is_err());
+        assert!(route_from_pair_ids(&pairs, &String::from("C"), &vec![key("C", "D")]).is_err());
+        assert!(route_from_pair_ids(&pairs, &token_in, &vec![key("A", "B"), key("A", "B")]).is_err());
+    }
+}
@@ -2,25 +2,92 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::sync::mpsc;
 
-#[derive(Copy, Clone, Serialize, Deserialize)]
-pub struct NetworkReference<'a> {
-    pub name: &'a str,
-    pub http: &'a str,
-    pub chain_id:  u64
-}
+use crate::data_sources::{DataSourceConfig, failover_data_source::FailoverConfig};
+use crate::utils::string_or_vec;
+use crate::http_client::HttpClientConfig;
+use crate::pruning::PruningConfig;
+use crate::pricing::PricingConfig;
+use crate::arbitrage::ArbitrageConfig;
+use crate::gas::{GasConfig, GasSample};
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Network {
     pub name: String,
-    pub http: String,
-    pub chain_id: u64
+    //One or more fullnode REST endpoints, in order of preference.
+    #[serde(deserialize_with = "string_or_vec")]
+    pub http: Vec<String>,
+    pub chain_id: u64,
+    #[serde(default)]
+    pub data_source: DataSourceConfig,
+    #[serde(default)]
+    pub client: HttpClientConfig,
+    #[serde(default)]
+    pub failover: FailoverConfig,
+    #[serde(default)]
+    pub update_mode: UpdateMode,
+    #[serde(default)]
+    pub pruning: PruningConfig,
+    #[serde(default)]
+    pub pricing: PricingConfig,
+    #[serde(default)]
+    pub gas: GasConfig,
+    #[serde(default)]
+    pub arbitrage: ArbitrageConfig
+}
+
+fn default_reconcile_interval_secs() -> u64 { 300 }
+
+//How the watcher keeps reserves up to date.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum UpdateMode {
+    //Apply the resources written by each transaction.
+    WriteSets,
+    //Apply swap/liquidity event deltas, for data sources without write-sets. Deltas can drift 
+    //from the real reserves (fees, rounding) so every reconcile_interval_secs we re-read all resources.
+    Events {
+        #[serde(default = "default_reconcile_interval_secs")]
+        reconcile_interval_secs: u64
+    }
+}
+
+impl Default for UpdateMode {
+    fn default() -> Self {
+        UpdateMode::WriteSets
+    }
 }
 
 pub struct ChannelRegistrysToWatch {
-    pub registrys_to_watch: Vec<String>
+    pub registrys_to_watch: Vec<String>,
+    //Event type prefixes the registrys can build metadata from, used in UpdateMode::Events.
+    pub event_types_to_watch: Vec<String>,
+    //(Swap event type prefix, Protocol), for gas calibration.
+    pub swap_event_types: Vec<(String, String)>
+}
+
+//A write-set change along with where it sits in the ledger, changes are applied in (version, index) order.
+#[derive(Clone)]
+pub struct VersionedChange {
+    pub version: u64,
+    pub index: usize,
+    pub change: Value
+}
+
+//An emitted event along with where it sits in the ledger, events are applied in (version, index) order.
+#[derive(Clone)]
+pub struct VersionedEvent {
+    pub version: u64,
+    pub index: usize,
+    pub event: Value
 }
 
 pub struct ChannelUpdateMetadata {
-    pub new_metadata: Option<Vec<Value>>,
+    pub new_metadata: Option<Vec<VersionedChange>>,
+    pub new_events: Option<Vec<VersionedEvent>>,
+    //Last ledger version the watcher has fully processed, sent once per batch.
+    pub version: Option<u64>,
+    //The watcher lost track of some versions, re-read all registry resources before applying version.
+    pub resync: bool,
+    pub gas_samples: Vec<GasSample>,
     pub channel_tx: Option<mpsc::Sender<ChannelRegistrysToWatch>>
 }
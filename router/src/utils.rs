@@ -1,14 +1,15 @@
 use std::{fs};
 use std::collections::HashMap;
+use std::rc::Rc;
+use std::cell::RefCell;
 
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::{json, Value};
 use anyhow::{Result, anyhow, Ok};
-// use reqwest::blocking::{Client, Response};
-use reqwest::{Client, Response};
-use reqwest;
 
-use crate::types::{Network, NetworkReference};
+use crate::types::Network;
+use crate::http_client::{HttpClient, HttpStatusError};
+use crate::pairs::{Pair, pair_from_value_descriptor};
 
 pub fn decimal_to_u64(float_val: f64, decimals: i32) -> u64 {
     let multiplier = 10f64.powi(decimals);
@@ -21,59 +22,119 @@ pub fn u64_to_decimal(val: u64, decimals: i32) -> f64 {
     return float_val/divisor;
 }
 
-//Todo: Implement RefCell pairs
-// pub fn read_pair_descriptors() -> Vec<PairTypes> {
-//     let data: String = fs::read_to_string("descriptors.json").expect("Failed to read file");
-//     let value_descriptors: Vec<Value> = serde_json::from_str(&data).unwrap();
-//     let mut typed_pairs:Vec<PairTypes> = Vec::new();
+pub const NETWORKS_PATH: &str = "networks.json";
+pub const REGISTRYS_PATH: &str = "registerys.json";
 
+//Single file every network shared before each got its own, still read when a network has no file yet.
+pub const LEGACY_DESCRIPTORS_PATH: &str = "descriptors.json";
 
-//     for value_descriptor in value_descriptors {
-//         let protocol = &*value_descriptor.get("protocol").unwrap().as_str().unwrap();
-//         match protocol {
-//             "pancake" => {
-//                 typed_pairs.push(
-//                     pancake_from_value_descriptor(value_descriptor)
-//                 )
-//             }
-//             _ => {
-//                 println!("Unknown Pair");
-//             }
-//         }
-//     }
+pub fn descriptors_path(network: &Network) -> String {
+    format!("descriptors.{}.json", network.name)
+}
+
+/*
+descriptors.json holds every pair we have discovered plus, per registry module address, the 
+high-water mark of the pair-created crawl so startup only has to look at newer events.
+Older files are a bare array of descriptors with no high-water marks.
+ */
+#[derive(Serialize, Deserialize, Default)]
+pub struct DescriptorStore {
+    pub high_water_marks: HashMap<String, u64>,
+    pub descriptors: Vec<Value>
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DescriptorFile {
+    Store(DescriptorStore),
+    Legacy(Vec<Value>)
+}
 
+pub fn read_pair_descriptors(path: &str) -> Result<(HashMap<String, u64>, Vec<Box<dyn Pair>>)> {
+    let data = match fs::read_to_string(path) {
+        std::result::Result::Ok(data) => data,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok((HashMap::new(), Vec::new())),
+        Err(error) => return Err(error.into())
+    };
+
+    let store = match serde_json::from_str::<DescriptorFile>(&data)? {
+        DescriptorFile::Store(store) => store,
+        DescriptorFile::Legacy(descriptors) => DescriptorStore { high_water_marks: HashMap::new(), descriptors: descriptors }
+    };
+
+    let mut pairs: Vec<Box<dyn Pair>> = Vec::new();
+    for value_descriptor in store.descriptors {
+        match pair_from_value_descriptor(value_descriptor) {
+            std::result::Result::Ok(pair) => pairs.push(pair),
+            Err(error) => println!("Skipping descriptor: {}", error)
+        }
+    }
 
-//     return typed_pairs;
-// }
+    return Ok((store.high_water_marks, pairs));
+}
 
+pub fn write_pair_descriptors(path: &str, high_water_marks: &HashMap<String, u64>, pairs: &Vec<Rc<RefCell<Box<dyn Pair>>>>) -> Result<()> {
+    let mut descriptors: Vec<Value> = Vec::new();
+    for pair in pairs {
+        let descriptor = (**pair).borrow().get_descriptor();
+        descriptors.push(serde_json::to_value(&*descriptor)?);
+    }
 
-//Todo: Implement RefCell pairs
-// pub fn write_pair_descriptors(pairs: &Vec<Box<dyn Pair>>) {
-//     let mut pair_descriptors:Vec<Box<dyn Descriptor>> = Vec::new();
-//     for pair in pairs {
-//         let protocol: &str = &*pair.get_protocol();
-//         match protocol {
-//             "pancake" => {
-//                 let pancake_pair = pair.as_any().downcast_ref::<PancakePair>().unwrap();
-//                 let descriptor = (*pancake_pair).get_descriptor();
-//                 pair_descriptors.push(descriptor);
-//             }
+    let store = DescriptorStore {
+        high_water_marks: high_water_marks.clone(),
+        descriptors: descriptors
+    };
 
-//             &_ => {
-                
-//             }
-//         }
-        
-        
-//     }
+    let json_str = serde_json::to_string_pretty(&store)?;
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, json_str)?;
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
 
-//     let data = json!(pair_descriptors);
+//Top level type arguments of a move type, eg. "<0x1::a::A, 0x2::b::B<0x1::a::A>>" -> ["0x1::a::A", "0x2::b::B<0x1::a::A>"]
+pub fn type_args(type_str: &str) -> Vec<String> {
+    let start = match type_str.find('<') {
+        Some(start) => start + 1,
+        None => return Vec::new()
+    };
+
+    let mut args: Vec<String> = Vec::new();
+    let mut depth = 0;
+    let mut arg_start = start;
+    for (i, c) in type_str.char_indices().skip_while(|(i, _)| *i < start) {
+        match c {
+            '<' => depth += 1,
+            '>' if depth == 0 => {
+                args.push(type_str[arg_start..i].trim().to_string());
+                break;
+            }
+            '>' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(type_str[arg_start..i].trim().to_string());
+                arg_start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    args
+}
 
-//     let json_str = data.to_string();
+//Reads a string encoded u64 field, eg. "amount_x_in": "1000"
+pub fn value_u64(value: &Value, field: &str) -> Option<u64> {
+    value.get(field).and_then(|x| x.as_str()).and_then(|x| x.parse::<u64>().ok())
+}
 
-//     fs::write("descriptors.json", json_str);
+//A string encoded u64 at a JSON pointer into a resource, eg. "/data/coin_x_reserve/value".
+pub fn pointer_u64(value: &Value, pointer: &str) -> Result<u64> {
+    let field = value.pointer(pointer).and_then(|x| x.as_str()).ok_or(anyhow!("Missing {}", pointer))?;
+    field.parse::<u64>().map_err(|error| anyhow!("Invalid {} {}: {}", pointer, field, error))
+}
 
-// }
+pub fn apply_reserve_delta(reserve: u64, added: u64, removed: u64) -> u64 {
+    reserve.saturating_add(added).saturating_sub(removed)
+}
 
 pub fn string_to_u64<'de, D>(deserializer: D) -> Result<u64, D::Error>
 where
@@ -91,13 +152,32 @@ where
     s.parse::<u128>().map_err(serde::de::Error::custom)
 }
 
+//Accepts either "value" or ["value", ...]
+pub fn string_or_vec<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrVec {
+        String(String),
+        Vec(Vec<String>)
+    }
+
+    match StringOrVec::deserialize(deserializer)? {
+        StringOrVec::String(s) => std::result::Result::Ok(vec![s]),
+        StringOrVec::Vec(v) => std::result::Result::Ok(v)
+    }
+}
+
 pub async fn query_aptos_events_raw(
+    client: &HttpClient,
     network_address: &str,
     account: &str,
     event: &str,
     start: u64,
     limit: u64
-) -> String {
+) -> Result<String> {
 
     // https://fullnode.mainnet.aptoslabs.com/v1 <-- Network Address
     // /accounts/
@@ -116,99 +196,66 @@ pub async fn query_aptos_events_raw(
     query.push_str("&limit=");
     query.push_str(limit.to_string().as_str());
 
-    let client = Client::new();
-
-    let resp: Response = client.get(query).send().await.unwrap();
-    let mut body: String = String::new();
-    if resp.status().is_success() {
-        body = resp.text().await.unwrap();
-        // println!("{}", body);
-    }
-    else {
-        println!("Faild with status code: {}", resp.status());
-    }
-    return body;
-   
+    client.get(&query).await
 }
 
 pub async fn query_aptos_resources_all_raw(
+    client: &HttpClient,
     network_address: &str,
     account: &str,
-) -> String {
+    ledger_version: Option<u64>,
+) -> Result<String> {
 
     let mut query = String::new();
     query.push_str(network_address);
     query.push_str("/accounts/");
     query.push_str(account);
     query.push_str("/resources");
-
-    let client = Client::new();
-
-    let resp: Response = client.get(query).send().await.unwrap();
-    let mut body: String = String::new();
-    if resp.status().is_success() {
-        body = resp.text().await.unwrap();
-        // println!("{}", body);
+    if let Some(ledger_version) = ledger_version {
+        query.push_str(&format!("?ledger_version={}", ledger_version));
     }
-    else {
-        println!("Faild with status code: {}", resp.status());
-    }
-    return body;
-   
+
+    client.get(&query).await
 }
 
-pub async fn query_aptos_transactions_by_version(network_address: &str, start: u64, limit: u16) -> Vec<Value> {
+pub async fn query_aptos_transactions_by_version(client: &HttpClient, network_address: &str, start: u64, limit: u16) -> Result<Vec<Value>> {
     let query = format!("{}/transactions/?start={}&limit={}", network_address, start, limit);
-    let client = Client::new();
-    
-    let resp: Response = client.get(query).send().await.unwrap();
-    let mut jsn: Value = Value::Null;
-    if resp.status().is_success() {
-        jsn = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
-    }
-    else if resp.status() == 400{
-        return Vec::new();
-    }
-    else {
-        println!("Faild with status code: {}", resp.status());
-    }
-    return jsn.as_array().unwrap().to_vec();
-}
-
-pub async fn get_aptos_version(network_address: &str) -> Result<u64> {
-    let query = format!("{}", network_address);
-    let client = Client::new();
-    let resp: Response = client.get(query).send().await.unwrap();
-    if resp.status().is_success() {
-        let jsn: Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
-        println!("{}", jsn);
-        let version = jsn.get("ledger_version").unwrap().as_str().unwrap().parse::<u64>().unwrap();
-        return Ok(version);
+
+    match client.get(&query).await {
+        std::result::Result::Ok(body) => {
+            let jsn: Value = serde_json::from_str(&body)?;
+            match jsn {
+                Value::Array(transactions) => Ok(transactions),
+                _ => Err(anyhow!("Expected an array of transactions"))
+            }
+        }
+        //The fullnode 400s when start is past the head of the chain.
+        Err(error) if error.downcast_ref::<HttpStatusError>().map_or(false, |x| x.status == 400) => Ok(Vec::new()),
+        Err(error) => Err(error)
     }
-    return Err(anyhow!("Failed to get version"));
 }
 
-pub fn get_network(name: String) -> Result<Network> {
-    let data: String = fs::read_to_string("networks.json").expect("Failed to read file");
-
-    let networks_as_reference: HashMap<String, NetworkReference> = serde_json::from_str(&data).unwrap();
+pub async fn get_aptos_version(client: &HttpClient, network_address: &str) -> Result<u64> {
+    let body = client.get(network_address).await?;
+    let jsn: Value = serde_json::from_str(&body)?;
+    let version = jsn.get("ledger_version")
+        .and_then(|x| x.as_str())
+        .ok_or(anyhow!("Failed to get version"))?
+        .parse::<u64>()?;
+    Ok(version)
+}
 
-    if !networks_as_reference.contains_key(&name){
-        return Err(anyhow!("Network does not exist"));
-    }
+pub fn get_networks(path: &str, names: &Vec<String>) -> Result<Vec<Network>> {
+    let data: String = fs::read_to_string(path).map_err(|error| anyhow!("Failed to read {}: {}", path, error))?;
 
-    let mut networks: HashMap<String, Network> = HashMap::new();
-    for (name_ref, data) in networks_as_reference.iter() {
-        let network = Network {
-            name: String::from(data.name),
-            http: String::from(data.http),
-            chain_id: data.chain_id
-        };
-        let name = String::from(name_ref);
+    let mut networks: HashMap<String, Network> = serde_json::from_str(&data)?;
 
-        networks.insert(name, network);
+    let mut selected: Vec<Network> = Vec::new();
+    for name in names {
+        match networks.remove(name) {
+            Some(network) => selected.push(network),
+            None => return Err(anyhow!("Network {} does not exist in {}", name, path))
+        }
     }
-
-
-    Ok(networks.get(&name).unwrap().clone())
+    Ok(selected)
 }
\ No newline at end of file